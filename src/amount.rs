@@ -0,0 +1,358 @@
+//! A compact fixed-point monetary value.
+//!
+//! Amounts are kept as `i64` scaled to 4 decimal places, which is half the size
+//! of `rust_decimal::Decimal` and still plenty of range for the balances.
+//!
+//! Amounts are read in two steps. The input is first only checked to be a number
+//! (`InputAmount`), and it is converted to an `Amount` once it is known how many
+//! decimal places are allowed and what to do with the excess ones (`Precision`).
+
+use std::{fmt, str::FromStr};
+
+use serde::Deserialize;
+use thiserror::Error;
+
+#[derive(Error, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ParseAmountError {
+    #[error("invalid amount")]
+    Invalid,
+    #[error("amount has more decimal places than allowed")]
+    TooManyDecimalPlaces,
+    #[error("amount is out of range")]
+    OutOfRange,
+}
+
+/// What happens with the amounts that have more decimal places than allowed.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ExcessPrecision {
+    #[default]
+    Reject,
+    /// Ties are rounded to the even neighbour, e.g. `0.125` to `0.12` and `0.135` to `0.14`.
+    RoundHalfEven,
+    /// Ties are rounded away from zero, e.g. `0.125` to `0.13`.
+    RoundHalfUp,
+    Truncate,
+}
+
+/// Number of decimal places of the amounts, both in the input and in the output.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Precision {
+    decimal_places: usize,
+    excess: ExcessPrecision,
+}
+
+impl Precision {
+    /// Returns `None` if the amounts can not be stored with that many decimal places.
+    pub fn new(decimal_places: usize, excess: ExcessPrecision) -> Option<Self> {
+        (decimal_places <= Amount::DECIMAL_PLACES).then_some(Self {
+            decimal_places,
+            excess,
+        })
+    }
+
+    pub fn decimal_places(&self) -> usize {
+        self.decimal_places
+    }
+}
+
+impl Default for Precision {
+    fn default() -> Self {
+        Self {
+            decimal_places: Amount::DECIMAL_PLACES,
+            excess: ExcessPrecision::Reject,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub struct Amount(i64);
+
+impl Amount {
+    pub const DECIMAL_PLACES: usize = 4;
+    pub(crate) const BYTES: usize = 8;
+    pub const ZERO: Self = Self(0);
+    #[cfg(test)]
+    pub(crate) const MAX: Self = Self(i64::MAX);
+    const SCALE: i64 = 10_000;
+
+    pub fn checked_add(self, other: Self) -> Option<Self> {
+        self.0.checked_add(other.0).map(Self)
+    }
+
+    pub fn checked_sub(self, other: Self) -> Option<Self> {
+        self.0.checked_sub(other.0).map(Self)
+    }
+
+    pub fn is_positive(self) -> bool {
+        self.0 > 0
+    }
+
+    pub fn is_negative(self) -> bool {
+        self.0 < 0
+    }
+
+    pub(crate) fn to_bytes(self) -> [u8; Self::BYTES] {
+        self.0.to_le_bytes()
+    }
+
+    pub(crate) fn from_bytes(bytes: [u8; Self::BYTES]) -> Self {
+        Self(i64::from_le_bytes(bytes))
+    }
+
+    /// Displays the amount with exactly `decimal_places`, digits past them are cut off.
+    pub fn fixed(self, decimal_places: usize) -> Fixed {
+        Fixed {
+            amount: self,
+            decimal_places: decimal_places.min(Self::DECIMAL_PLACES),
+        }
+    }
+
+    fn split(self) -> (&'static str, u64, u64) {
+        let sign = if self.is_negative() { "-" } else { "" };
+        let value = self.0.unsigned_abs();
+        let scale = Self::SCALE.unsigned_abs();
+        (sign, value / scale, value % scale)
+    }
+}
+
+impl From<u32> for Amount {
+    fn from(value: u32) -> Self {
+        Self(i64::from(value) * Self::SCALE)
+    }
+}
+
+/// Parses the amount with the default `Precision`.
+impl FromStr for Amount {
+    type Err = ParseAmountError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.parse::<InputAmount>()?.to_amount(Precision::default())
+    }
+}
+
+// Trailing zeros of the fraction are not printed, e.g. `5.5000` is printed as `5.5`.
+impl fmt::Display for Amount {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (sign, integer, fraction) = self.split();
+        if fraction == 0 {
+            return write!(f, "{sign}{integer}");
+        }
+        let fraction = format!("{:0width$}", fraction, width = Self::DECIMAL_PLACES);
+        write!(f, "{sign}{integer}.{}", fraction.trim_end_matches('0'))
+    }
+}
+
+/// An amount displayed with a fixed number of decimal places, e.g. `5.5000`.
+#[derive(Debug, Clone, Copy)]
+pub struct Fixed {
+    amount: Amount,
+    decimal_places: usize,
+}
+
+impl Fixed {
+    /// The amount, including the digits which are not displayed.
+    pub fn amount(&self) -> Amount {
+        self.amount
+    }
+}
+
+impl fmt::Display for Fixed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (sign, integer, fraction) = self.amount.split();
+        if self.decimal_places == 0 {
+            return write!(f, "{sign}{integer}");
+        }
+        let fraction = format!("{:0width$}", fraction, width = Amount::DECIMAL_PLACES);
+        write!(f, "{sign}{integer}.{}", &fraction[..self.decimal_places])
+    }
+}
+
+impl serde::Serialize for Fixed {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        serializer.collect_str(self)
+    }
+}
+
+// One more fraction digit than can be stored, to know how to round.
+const FRACTION_DIGITS: usize = Amount::DECIMAL_PLACES + 1;
+
+/// An amount as written in the input, not yet converted to `Amount`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InputAmount {
+    negative: bool,
+    integer: u64,
+    // Leading digits of the fraction, e.g. `[1, 2, 0, 0, 0]` for `.12`.
+    fraction: [u8; FRACTION_DIGITS],
+    // Whether there are any non-zero digits past `fraction`.
+    sticky: bool,
+}
+
+impl InputAmount {
+    /// Converts to an `Amount` with the given precision, rounding if allowed.
+    pub fn to_amount(self, precision: Precision) -> Result<Amount, ParseAmountError> {
+        let places = precision.decimal_places;
+        let scaled = self.fraction[..places]
+            .iter()
+            .fold(u128::from(self.integer), |acc, &digit| {
+                acc * 10 + u128::from(digit)
+            });
+        let next = self.fraction[places];
+        let beyond = self.sticky || self.fraction[places + 1..].iter().any(|&digit| digit != 0);
+        let round_up = match (next != 0 || beyond, precision.excess) {
+            (false, _) => false,
+            (true, ExcessPrecision::Reject) => return Err(ParseAmountError::TooManyDecimalPlaces),
+            (true, ExcessPrecision::Truncate) => false,
+            (true, ExcessPrecision::RoundHalfUp) => next >= 5,
+            (true, ExcessPrecision::RoundHalfEven) => {
+                next > 5 || (next == 5 && (beyond || scaled % 2 == 1))
+            }
+        };
+        let scaled =
+            (scaled + u128::from(round_up)) * 10_u128.pow((Amount::DECIMAL_PLACES - places) as u32);
+        let value = i64::try_from(scaled).map_err(|_| ParseAmountError::OutOfRange)?;
+        Ok(Amount(if self.negative { -value } else { value }))
+    }
+}
+
+/// Accepts an optional sign, digits and an optional fraction, e.g. `-12.5`, `.5` or `10.`.
+impl FromStr for InputAmount {
+    type Err = ParseAmountError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (negative, digits) = match s.strip_prefix('-') {
+            Some(digits) => (true, digits),
+            None => (false, s.strip_prefix('+').unwrap_or(s)),
+        };
+        let (integer, fraction) = digits.split_once('.').unwrap_or((digits, ""));
+        if integer.is_empty() && fraction.is_empty() {
+            return Err(ParseAmountError::Invalid);
+        }
+        if !integer
+            .bytes()
+            .chain(fraction.bytes())
+            .all(|b| b.is_ascii_digit())
+        {
+            return Err(ParseAmountError::Invalid);
+        }
+
+        // Anything that does not fit into `u64` is out of range anyway. Saturating keeps
+        // the syntax check separate, so the range is only reported by `to_amount`.
+        let integer = match integer.trim_start_matches('0') {
+            "" => 0,
+            integer => integer.parse().unwrap_or(u64::MAX),
+        };
+        let mut digits = [0; FRACTION_DIGITS];
+        for (digit, b) in digits.iter_mut().zip(fraction.bytes()) {
+            *digit = b - b'0';
+        }
+        let sticky = fraction.bytes().skip(FRACTION_DIGITS).any(|b| b != b'0');
+        Ok(Self {
+            negative,
+            integer,
+            fraction: digits,
+            sticky,
+        })
+    }
+}
+
+impl<'de> Deserialize<'de> for InputAmount {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        struct Visitor;
+
+        impl serde::de::Visitor<'_> for Visitor {
+            type Value = InputAmount;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a decimal number")
+            }
+
+            fn visit_str<E>(self, v: &str) -> Result<Self::Value, E>
+            where
+                E: serde::de::Error,
+            {
+                v.parse()
+                    .map_err(|err| E::custom(format!("{}: {}", err, v)))
+            }
+        }
+
+        // Parsed straight from the field, without allocating a `String`.
+        deserializer.deserialize_str(Visitor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use test_case::test_case;
+
+    use super::{Amount, ExcessPrecision, InputAmount, ParseAmountError, Precision};
+
+    #[test_case("1" => Ok("1".to_string()) ; "integer")]
+    #[test_case("1.5" => Ok("1.5".to_string()) ; "fraction")]
+    #[test_case("0.0001" => Ok("0.0001".to_string()) ; "smallest")]
+    #[test_case(".5" => Ok("0.5".to_string()) ; "no integer part")]
+    #[test_case("10." => Ok("10".to_string()) ; "no fraction")]
+    #[test_case("-2.25" => Ok("-2.25".to_string()) ; "negative")]
+    #[test_case("+3" => Ok("3".to_string()) ; "explicit sign")]
+    #[test_case("5.000000" => Ok("5".to_string()) ; "trailing zeros")]
+    #[test_case("922337203685477.5807" => Ok("922337203685477.5807".to_string()) ; "max")]
+    #[test_case("1.11111" => Err(ParseAmountError::TooManyDecimalPlaces) ; "too many decimal places")]
+    #[test_case("922337203685477.5808" => Err(ParseAmountError::OutOfRange) ; "out of range")]
+    #[test_case("99999999999999999999999" => Err(ParseAmountError::OutOfRange) ; "larger than u64")]
+    #[test_case("" => Err(ParseAmountError::Invalid) ; "empty")]
+    #[test_case("." => Err(ParseAmountError::Invalid) ; "only dot")]
+    #[test_case("1e3" => Err(ParseAmountError::Invalid) ; "exponent")]
+    #[test_case("ABC" => Err(ParseAmountError::Invalid) ; "not a number")]
+    fn parse(s: &str) -> Result<String, ParseAmountError> {
+        s.parse::<Amount>().map(|amount| amount.to_string())
+    }
+
+    #[test_case("0.125", ExcessPrecision::RoundHalfEven => Ok("0.12".to_string()) ; "half even tie down")]
+    #[test_case("0.135", ExcessPrecision::RoundHalfEven => Ok("0.14".to_string()) ; "half even tie up")]
+    #[test_case("0.12500001", ExcessPrecision::RoundHalfEven => Ok("0.13".to_string()) ; "half even above tie")]
+    #[test_case("0.125", ExcessPrecision::RoundHalfUp => Ok("0.13".to_string()) ; "half up tie")]
+    #[test_case("0.124", ExcessPrecision::RoundHalfUp => Ok("0.12".to_string()) ; "half up below tie")]
+    #[test_case("-0.125", ExcessPrecision::RoundHalfUp => Ok("-0.13".to_string()) ; "half up negative")]
+    #[test_case("0.129", ExcessPrecision::Truncate => Ok("0.12".to_string()) ; "truncate")]
+    #[test_case("0.1200", ExcessPrecision::Reject => Ok("0.12".to_string()) ; "reject trailing zeros")]
+    #[test_case("0.121", ExcessPrecision::Reject => Err(ParseAmountError::TooManyDecimalPlaces) ; "reject")]
+    #[test_case("9.995", ExcessPrecision::RoundHalfUp => Ok("10".to_string()) ; "carry")]
+    fn two_decimal_places(s: &str, excess: ExcessPrecision) -> Result<String, ParseAmountError> {
+        let precision = Precision::new(2, excess).unwrap();
+        let input: InputAmount = s.parse().unwrap();
+        input.to_amount(precision).map(|amount| amount.to_string())
+    }
+
+    #[test]
+    fn oversized_input_is_valid_syntax() {
+        let input: InputAmount = "99999999999999999999999.9".parse().unwrap();
+        assert_eq!(
+            input.to_amount(Precision::default()),
+            Err(ParseAmountError::OutOfRange)
+        );
+    }
+
+    #[test]
+    fn more_decimal_places_than_stored() {
+        assert_eq!(
+            Precision::new(Amount::DECIMAL_PLACES + 1, ExcessPrecision::Reject),
+            None
+        );
+    }
+
+    #[test_case("5", 4 => "5.0000" ; "integer")]
+    #[test_case("5.5", 2 => "5.50" ; "fraction")]
+    #[test_case("5.5", 0 => "5" ; "no decimal places")]
+    #[test_case("-0.1", 4 => "-0.1000" ; "negative")]
+    fn fixed(s: &str, decimal_places: usize) -> String {
+        s.parse::<Amount>()
+            .unwrap()
+            .fixed(decimal_places)
+            .to_string()
+    }
+}
@@ -1,19 +1,22 @@
 //! Balances module helps to track and update all balances.
 //!
 //! It works with any value that implements the `BalanceUpdater` trait. It does not store
-//! the `total` balance as it can always be derived from `held` and `available`.
+//! the `total` balance as it can always be derived from `held` and `available`. Updates
+//! which would make the total overflow are rejected, so that it can always be reported.
 
 use thiserror::Error;
 
 use crate::NonNegative;
 
 #[derive(Error, Debug)]
-pub(super) enum Error {
+pub(crate) enum Error {
     #[error("Arithmetic overflow when updating balances")]
     ArithmeticOverflow,
+    #[error("Insufficient funds")]
+    InsufficientFunds,
 }
 
-pub(super) trait BalanceUpdater
+pub(crate) trait BalanceUpdater
 where
     Self: Sized,
 {
@@ -23,21 +26,20 @@ where
 }
 
 #[derive(Debug, Clone)]
-pub(super) struct Balances {
+pub struct Balances {
     available: NonNegative,
     held: NonNegative,
 }
 
 impl Balances {
-    pub(super) fn new() -> Self {
+    pub(crate) fn new() -> Self {
         Self {
             available: NonNegative::new(),
             held: NonNegative::new(),
         }
     }
 
-    #[cfg(test)]
-    fn new_with_values(available: NonNegative, held: NonNegative) -> Self {
+    pub(crate) fn new_with_values(available: NonNegative, held: NonNegative) -> Self {
         Self { available, held }
     }
 
@@ -45,58 +47,91 @@ impl Balances {
         from: NonNegative,
         to: NonNegative,
         amount: NonNegative,
-    ) -> Option<(NonNegative, NonNegative)> {
-        let new_from = from.sub(amount)?;
-        let new_to = to.add(amount)?;
-        Some((new_from, new_to))
+    ) -> Result<(NonNegative, NonNegative), Error> {
+        let new_from = from.sub(amount).ok_or(Error::InsufficientFunds)?;
+        let new_to = to.add(amount).ok_or(Error::ArithmeticOverflow)?;
+        Ok((new_from, new_to))
     }
 
-    pub(super) fn deposit(&mut self, amount: NonNegative) -> Result<(), Error> {
-        self.available = self
+    // Only deposits and disputed withdrawals raise the total, the other updates move funds
+    // between `available` and `held` or take them away.
+    fn ensure_total(available: NonNegative, held: NonNegative) -> Result<(), Error> {
+        available
+            .add(held)
+            .map(|_| ())
+            .ok_or(Error::ArithmeticOverflow)
+    }
+
+    pub(crate) fn deposit(&mut self, amount: NonNegative) -> Result<(), Error> {
+        let available = self
             .available
             .add(amount)
             .ok_or(Error::ArithmeticOverflow)?;
+        Self::ensure_total(available, self.held)?;
+        self.available = available;
         Ok(())
     }
 
-    pub(super) fn withdrawal(&mut self, amount: NonNegative) -> Result<(), Error> {
-        self.available = self
-            .available
-            .sub(amount)
-            .ok_or(Error::ArithmeticOverflow)?;
+    pub(crate) fn withdrawal(&mut self, amount: NonNegative) -> Result<(), Error> {
+        self.available = self.available.sub(amount).ok_or(Error::InsufficientFunds)?;
         Ok(())
     }
 
-    pub(super) fn dispute(&mut self, amount: NonNegative) -> Result<(), Error> {
-        let (new_available, new_held) =
-            Self::transfer(self.available, self.held, amount).ok_or(Error::ArithmeticOverflow)?;
+    pub(crate) fn dispute(&mut self, amount: NonNegative) -> Result<(), Error> {
+        let (new_available, new_held) = Self::transfer(self.available, self.held, amount)?;
 
         self.held = new_held;
         self.available = new_available;
         Ok(())
     }
 
-    pub(super) fn resolve(&mut self, amount: NonNegative) -> Result<(), Error> {
-        let (new_held, new_available) =
-            Self::transfer(self.held, self.available, amount).ok_or(Error::ArithmeticOverflow)?;
+    pub(crate) fn resolve(&mut self, amount: NonNegative) -> Result<(), Error> {
+        let (new_held, new_available) = Self::transfer(self.held, self.available, amount)?;
 
         self.held = new_held;
         self.available = new_available;
         Ok(())
     }
 
-    pub(super) fn chargeback(&mut self, amount: NonNegative) -> Result<(), Error> {
-        self.held = self.held.sub(amount).ok_or(Error::ArithmeticOverflow)?;
+    pub(crate) fn chargeback(&mut self, amount: NonNegative) -> Result<(), Error> {
+        self.held = self.held.sub(amount).ok_or(Error::InsufficientFunds)?;
+        Ok(())
+    }
+
+    // Disputed withdrawal is held on top of the available funds, it has already left them.
+    pub(crate) fn dispute_withdrawal(&mut self, amount: NonNegative) -> Result<(), Error> {
+        let held = self.held.add(amount).ok_or(Error::ArithmeticOverflow)?;
+        Self::ensure_total(self.available, held)?;
+        self.held = held;
         Ok(())
     }
 
-    pub(super) fn available(&self) -> NonNegative {
+    pub(crate) fn resolve_withdrawal(&mut self, amount: NonNegative) -> Result<(), Error> {
+        self.held = self.held.sub(amount).ok_or(Error::InsufficientFunds)?;
+        Ok(())
+    }
+
+    pub(crate) fn chargeback_withdrawal(&mut self, amount: NonNegative) -> Result<(), Error> {
+        let (new_held, new_available) = Self::transfer(self.held, self.available, amount)?;
+
+        self.held = new_held;
+        self.available = new_available;
+        Ok(())
+    }
+
+    pub fn available(&self) -> NonNegative {
         self.available
     }
 
-    pub(super) fn held(&self) -> NonNegative {
+    pub fn held(&self) -> NonNegative {
         self.held
     }
+
+    /// Sum of the available and held funds. `None` if it does not fit into an `Amount`,
+    /// which the updates never allow.
+    pub fn total(&self) -> Option<NonNegative> {
+        self.available.add(self.held)
+    }
 }
 
 #[cfg(test)]
@@ -152,6 +187,33 @@ mod tests {
             assert!(balance.chargeback(1.into()).is_ok());
             assert_eq!(balance.held, 199.into());
         }
+
+        #[test]
+        fn dispute_withdrawal() {
+            let mut balance = new_balance(100.into(), 200.into());
+
+            assert!(balance.dispute_withdrawal(1.into()).is_ok());
+            assert_eq!(balance.held, 201.into());
+            assert_eq!(balance.available, 100.into());
+        }
+
+        #[test]
+        fn resolve_withdrawal() {
+            let mut balance = new_balance(100.into(), 200.into());
+
+            assert!(balance.resolve_withdrawal(1.into()).is_ok());
+            assert_eq!(balance.held, 199.into());
+            assert_eq!(balance.available, 100.into());
+        }
+
+        #[test]
+        fn chargeback_withdrawal() {
+            let mut balance = new_balance(100.into(), 200.into());
+
+            assert!(balance.chargeback_withdrawal(1.into()).is_ok());
+            assert_eq!(balance.held, 199.into());
+            assert_eq!(balance.available, 101.into());
+        }
     }
 
     mod overflow_with_non_negative_type {
@@ -170,41 +232,96 @@ mod tests {
         }
 
         #[test]
-        fn withdrawal() {
-            let mut balance = new_balance(NonNegative::MIN, 100.into());
+        fn dispute() {
+            let mut balance = new_balance(100.into(), NonNegative::MAX);
             assert!(matches!(
-                balance.withdrawal(1.into()),
+                balance.dispute(1.into()),
                 Err(Error::ArithmeticOverflow)
             ));
         }
 
         #[test]
-        fn dispute() {
+        fn resolve() {
+            let mut balance = new_balance(NonNegative::MAX, 100.into());
+            assert!(matches!(
+                balance.resolve(1.into()),
+                Err(Error::ArithmeticOverflow)
+            ));
+        }
+
+        #[test]
+        fn dispute_withdrawal() {
             let mut balance = new_balance(100.into(), NonNegative::MAX);
             assert!(matches!(
-                balance.dispute(1.into()),
+                balance.dispute_withdrawal(1.into()),
                 Err(Error::ArithmeticOverflow)
             ));
+        }
+    }
 
-            let mut balance = new_balance(NonNegative::MIN, 100.into());
+    mod total_overflow {
+        use crate::{
+            NonNegative,
+            balances::{BalanceUpdater, Error, tests::new_balance},
+        };
+
+        // The total is exactly the maximum.
+        fn full() -> crate::Balances {
+            new_balance(1.into(), NonNegative::MAX.sub(1.into()).unwrap())
+        }
+
+        #[test]
+        fn deposit() {
+            let mut balance = full();
             assert!(matches!(
-                balance.dispute(1.into()),
+                balance.deposit(1.into()),
                 Err(Error::ArithmeticOverflow)
             ));
+            assert_eq!(balance.available, 1.into());
+            assert!(balance.total().is_some());
         }
 
         #[test]
-        fn resolve() {
-            let mut balance = new_balance(100.into(), NonNegative::MIN);
+        fn dispute_withdrawal() {
+            let mut balance = full();
             assert!(matches!(
-                balance.resolve(1.into()),
+                balance.dispute_withdrawal(1.into()),
                 Err(Error::ArithmeticOverflow)
             ));
+            assert_eq!(balance.held, NonNegative::MAX.sub(1.into()).unwrap());
+        }
+    }
 
-            let mut balance = new_balance(NonNegative::MAX, 100.into());
+    mod insufficient_funds {
+        use crate::{
+            NonNegative,
+            balances::{Error, tests::new_balance},
+        };
+
+        #[test]
+        fn withdrawal() {
+            let mut balance = new_balance(NonNegative::MIN, 100.into());
+            assert!(matches!(
+                balance.withdrawal(1.into()),
+                Err(Error::InsufficientFunds)
+            ));
+        }
+
+        #[test]
+        fn dispute() {
+            let mut balance = new_balance(NonNegative::MIN, 100.into());
+            assert!(matches!(
+                balance.dispute(1.into()),
+                Err(Error::InsufficientFunds)
+            ));
+        }
+
+        #[test]
+        fn resolve() {
+            let mut balance = new_balance(100.into(), NonNegative::MIN);
             assert!(matches!(
                 balance.resolve(1.into()),
-                Err(Error::ArithmeticOverflow)
+                Err(Error::InsufficientFunds)
             ));
         }
 
@@ -213,7 +330,16 @@ mod tests {
             let mut balance = new_balance(100.into(), NonNegative::MIN);
             assert!(matches!(
                 balance.chargeback(1.into()),
-                Err(Error::ArithmeticOverflow)
+                Err(Error::InsufficientFunds)
+            ));
+        }
+
+        #[test]
+        fn resolve_withdrawal() {
+            let mut balance = new_balance(100.into(), NonNegative::MIN);
+            assert!(matches!(
+                balance.resolve_withdrawal(1.into()),
+                Err(Error::InsufficientFunds)
             ));
         }
     }
@@ -226,6 +352,6 @@ mod tests {
         let to: NonNegative = to.into();
         let amount: NonNegative = amount.into();
 
-        Balances::transfer(from, to, amount)
+        Balances::transfer(from, to, amount).ok()
     }
 }
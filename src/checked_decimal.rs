@@ -1,20 +1,20 @@
-//! A helper wrappers around `rust_decimal::Decimal` that ensures various properties
+//! A helper wrappers around the fixed-point `Amount` that ensures various properties
 //! like non-zero or non-negative values.
 
-use rust_decimal::Decimal;
-use serde::{Deserialize, Serialize};
-
-use crate::balances::BalanceUpdater;
+use crate::{
+    amount::{Amount, Fixed},
+    balances::BalanceUpdater,
+};
 
 // TODO: Could potentially use std::num::NonZero
-#[derive(Debug, Clone, Copy, Serialize, Deserialize, Eq, PartialEq)]
-pub(super) struct NonZero(Decimal);
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct NonZero(Amount);
 
-impl TryFrom<Decimal> for NonZero {
+impl TryFrom<Amount> for NonZero {
     type Error = ();
 
-    fn try_from(value: Decimal) -> Result<Self, Self::Error> {
-        if value > Decimal::ZERO {
+    fn try_from(value: Amount) -> Result<Self, Self::Error> {
+        if value.is_positive() {
             Ok(Self(value))
         } else {
             Err(())
@@ -22,13 +22,50 @@ impl TryFrom<Decimal> for NonZero {
     }
 }
 
-#[derive(Debug, Clone, Copy, Serialize, Deserialize, Eq, PartialEq)]
-pub(super) struct NonNegative(Decimal);
+impl NonZero {
+    pub(crate) const BYTES: usize = Amount::BYTES;
+
+    pub(crate) fn to_bytes(self) -> [u8; Self::BYTES] {
+        self.0.to_bytes()
+    }
+
+    // Bytes that do not represent a positive value are rejected, same as in `try_from`.
+    pub(crate) fn from_bytes(bytes: [u8; Self::BYTES]) -> Option<Self> {
+        Self::try_from(Amount::from_bytes(bytes)).ok()
+    }
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct NonNegative(Amount);
+
+impl TryFrom<Amount> for NonNegative {
+    type Error = ();
+
+    fn try_from(value: Amount) -> Result<Self, Self::Error> {
+        if value.is_negative() {
+            Err(())
+        } else {
+            Ok(Self(value))
+        }
+    }
+}
+
+impl NonNegative {
+    pub fn fixed(self, decimal_places: usize) -> Fixed {
+        self.0.fixed(decimal_places)
+    }
+}
 
 #[cfg(test)]
 impl NonNegative {
-    pub(super) const MIN: Self = Self(Decimal::ZERO);
-    pub(super) const MAX: Self = Self(Decimal::MAX);
+    pub(crate) const MIN: Self = Self(Amount::ZERO);
+    pub(crate) const MAX: Self = Self(Amount::MAX);
+}
+
+impl From<NonZero> for Amount {
+    fn from(value: NonZero) -> Self {
+        value.0
+    }
 }
 
 impl From<NonZero> for NonNegative {
@@ -43,9 +80,15 @@ impl From<&NonZero> for NonNegative {
     }
 }
 
+impl From<NonNegative> for Amount {
+    fn from(value: NonNegative) -> Self {
+        value.0
+    }
+}
+
 impl BalanceUpdater for NonNegative {
     fn new() -> Self {
-        Self(Decimal::ZERO)
+        Self(Amount::ZERO)
     }
 
     fn add(self, other: Self) -> Option<Self> {
@@ -54,42 +97,51 @@ impl BalanceUpdater for NonNegative {
 
     fn sub(self, other: Self) -> Option<Self> {
         let new_value = self.0.checked_sub(other.0);
-        new_value.and_then(|v| v.is_sign_positive().then_some(Self(v)))
+        new_value.and_then(|v| (!v.is_negative()).then_some(Self(v)))
     }
 }
 
 impl std::convert::From<u32> for NonNegative {
     fn from(value: u32) -> Self {
-        Self(Decimal::from(value))
+        Self(Amount::from(value))
     }
 }
 
 #[cfg(test)]
 mod tests {
     mod non_zero {
-        use rust_decimal::Decimal;
-
-        use crate::NonZero;
+        use crate::{NonZero, amount::Amount};
 
         #[test]
         fn can_not_be_zero() {
-            let zero = Decimal::ZERO;
+            let zero = Amount::ZERO;
             let non_zero = NonZero::try_from(zero);
             assert!(non_zero.is_err());
         }
 
         #[test]
         fn can_not_be_negative() {
-            let negative = Decimal::MIN;
+            let negative = "-1.5".parse::<Amount>().unwrap();
             let non_zero = NonZero::try_from(negative);
             assert!(non_zero.is_err());
         }
+
+        #[test]
+        fn bytes_roundtrip() {
+            let non_zero = NonZero::try_from("1.2345".parse::<Amount>().unwrap()).unwrap();
+            assert_eq!(NonZero::from_bytes(non_zero.to_bytes()), Some(non_zero));
+        }
+
+        #[test]
+        fn zeroed_bytes_are_rejected() {
+            assert_eq!(NonZero::from_bytes([0; NonZero::BYTES]), None);
+        }
     }
 
     mod non_negative {
         use test_case::test_case;
 
-        use crate::{BalanceUpdater, NonNegative};
+        use crate::{NonNegative, balances::BalanceUpdater};
 
         #[test_case(10.into(), 5.into() => Some(15.into()))]
         #[test_case(0.into(), 0.into() => Some(0.into()))]
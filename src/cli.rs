@@ -0,0 +1,293 @@
+//! Command line arguments of the transaction processor.
+
+use std::{io, net::SocketAddr, num::NonZeroUsize, path::PathBuf, sync::Arc, time::Duration};
+
+use clap::{Parser, ValueEnum};
+use tokio::io::AsyncRead;
+
+use crate::compression;
+
+use tx_processor::{
+    Amount, ExcessPrecision, Precision, WithdrawalDisputePolicy,
+    db::{Backend, in_mem::PruningStrategy, on_disk},
+};
+
+#[derive(Debug, Parser)]
+#[command(about = "Processes CSV files with transactions and prints the final client states")]
+pub(super) struct Args {
+    /// CSV files with the transactions, or glob patterns matching them. They are processed
+    /// in the given order as a single stream, the files matching a pattern alphabetically.
+    /// `-` or no input at all reads from `stdin`.
+    #[arg(value_name = "INPUT", conflicts_with = "serve")]
+    inputs: Vec<String>,
+
+    /// Instead of reading the inputs, accept transaction streams in the input format over
+    /// TCP connections on this address, e.g. `127.0.0.1:7878`. The final client states are
+    /// written once interrupted with Ctrl-C.
+    #[arg(long, value_name = "ADDR")]
+    pub(super) serve: Option<SocketAddr>,
+
+    /// In the serve mode, answer `GET /clients/{id}` requests with the current state of
+    /// the client on this address, e.g. `127.0.0.1:8080`.
+    #[arg(
+        long,
+        value_name = "ADDR",
+        requires = "serve",
+        conflicts_with = "inputs"
+    )]
+    pub(super) http: Option<SocketAddr>,
+
+    /// Format of the inputs.
+    #[arg(long, value_enum, default_value_t = InputFormat::Csv)]
+    pub(super) input_format: InputFormat,
+
+    /// Format of the final client states. The JSON formats also include the number of
+    /// open disputes.
+    #[arg(long, value_enum, default_value_t = OutputFormat::Csv)]
+    pub(super) output_format: OutputFormat,
+
+    /// Order of the final client states. Ties are broken by the client ID and the currency,
+    /// so the same input always produces the same output.
+    #[arg(long, value_enum, default_value_t = SortBy::Client)]
+    pub(super) sort_by: SortBy,
+
+    /// Write the final client states to this file instead of `stdout`. The file is overwritten.
+    #[arg(long, short, value_name = "PATH")]
+    pub(super) output: Option<PathBuf>,
+
+    /// Forget deposits older than the given number of seconds. They can no longer be disputed.
+    #[arg(long, value_name = "SECONDS", conflicts_with = "max_cached_deposits")]
+    prune_after: Option<u64>,
+
+    /// Keep at most this many deposits per client, forgetting the oldest ones.
+    /// They can no longer be disputed.
+    #[arg(long, value_name = "COUNT")]
+    max_cached_deposits: Option<NonZeroUsize>,
+
+    /// Keep the deposits in the given file instead of in memory. The file is
+    /// overwritten. Useful when the deposits do not fit in RAM.
+    #[arg(
+        long,
+        value_name = "PATH",
+        conflicts_with_all = ["prune_after", "max_cached_deposits"]
+    )]
+    deposit_cache_file: Option<PathBuf>,
+
+    /// Checkpoint the state in this directory, so that an interrupted run can be resumed
+    /// with `--resume`, and keep an audit log of the applied transactions there. Not
+    /// available with the deposits kept on disk.
+    #[arg(
+        long,
+        value_name = "DIR",
+        conflicts_with_all = ["serve", "deposit_cache_file"]
+    )]
+    pub(super) recovery_dir: Option<PathBuf>,
+
+    /// Number of records between two checkpoints.
+    #[arg(long, value_name = "RECORDS", default_value = "100000")]
+    pub(super) checkpoint_every: NonZeroUsize,
+
+    /// Continue from the last checkpoint in the recovery directory, skipping the records
+    /// which were already processed. The inputs must be the same, in the same order. The
+    /// rejections and outcomes are appended to the files of the interrupted run, cut back to
+    /// the checkpoint.
+    #[arg(long, requires = "recovery_dir")]
+    pub(super) resume: bool,
+
+    /// Start from the state of all clients in this snapshot, e.g. written by the previous
+    /// run with `--export-snapshot`. Not available with the deposits kept on disk.
+    #[arg(long, value_name = "PATH", conflicts_with = "deposit_cache_file")]
+    pub(super) import_snapshot: Option<PathBuf>,
+
+    /// Write the state of all clients to this snapshot once all inputs are processed, so
+    /// that the next run can start from it with `--import-snapshot`. Not available with the
+    /// deposits kept on disk.
+    #[arg(long, value_name = "PATH", conflicts_with = "deposit_cache_file")]
+    pub(super) export_snapshot: Option<PathBuf>,
+
+    /// Number of worker tasks the clients are spread over. By default every client
+    /// gets its own task.
+    #[arg(long, value_name = "COUNT")]
+    pub(super) shards: Option<NonZeroUsize>,
+
+    /// Write the records which were not applied, together with the reason, to this CSV file.
+    #[arg(long, value_name = "PATH")]
+    pub(super) rejections: Option<PathBuf>,
+
+    /// Write the outcome of every transaction handled by the clients, applied or rejected,
+    /// together with the balances right after it, to this CSV file.
+    #[arg(long, value_name = "PATH")]
+    pub(super) outcomes: Option<PathBuf>,
+
+    /// Write logs to this file instead of `stderr`. Verbosity is controlled with `RUST_LOG`.
+    #[arg(long, value_name = "PATH")]
+    pub(super) log_file: Option<PathBuf>,
+
+    /// Whether withdrawals can be disputed, and what happens with the funds if they are.
+    #[arg(long, value_enum, default_value_t = WithdrawalDisputes::Reject)]
+    withdrawal_disputes: WithdrawalDisputes,
+
+    /// Number of decimal places of the amounts. The output always has exactly this many.
+    #[arg(
+        long,
+        value_name = "COUNT",
+        default_value_t = Amount::DECIMAL_PLACES as u8,
+        value_parser = clap::value_parser!(u8).range(..=Amount::DECIMAL_PLACES as i64)
+    )]
+    decimal_places: u8,
+
+    /// What happens with the input amounts that have more decimal places.
+    #[arg(long, value_enum, default_value_t = ExcessDecimalPlaces::Reject)]
+    excess_decimal_places: ExcessDecimalPlaces,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub(super) enum InputFormat {
+    /// CSV with a header.
+    Csv,
+    /// JSON Lines, an object with the same fields as the CSV columns on every line.
+    Jsonl,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub(super) enum OutputFormat {
+    /// CSV with a header.
+    Csv,
+    /// A single JSON array of objects.
+    Json,
+    /// JSON Lines, an object on every line.
+    Jsonl,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub(super) enum SortBy {
+    /// Ascending client ID.
+    Client,
+    /// Descending total balance.
+    TotalDesc,
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum ExcessDecimalPlaces {
+    /// The record is rejected.
+    Reject,
+    /// The amount is rounded, ties to the even neighbour.
+    RoundHalfEven,
+    /// The amount is rounded, ties away from zero.
+    RoundHalfUp,
+    /// The excess decimal places are cut off.
+    Truncate,
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum WithdrawalDisputes {
+    /// Only deposits can be disputed.
+    Reject,
+    /// The disputed amount is held until the dispute is resolved or charged back.
+    Hold,
+    /// Nothing is held, the amount is credited back on chargeback.
+    NoHold,
+}
+
+/// Where the transactions are read from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(super) enum Input {
+    Stdin,
+    File(PathBuf),
+}
+
+impl Input {
+    /// Name of the input in the rejections.
+    pub(super) fn name(&self) -> String {
+        match self {
+            Self::Stdin => STDIN.to_string(),
+            Self::File(path) => path.to_string_lossy().into_owned(),
+        }
+    }
+
+    /// Opens the input, decompressing it if needed.
+    pub(super) async fn open(&self) -> io::Result<Box<dyn AsyncRead + Unpin + Send>> {
+        match self {
+            Self::Stdin => compression::decompressed(tokio::io::stdin()).await,
+            Self::File(path) => compression::decompressed(tokio::fs::File::open(path).await?).await,
+        }
+    }
+}
+
+const STDIN: &str = "-";
+
+impl Args {
+    /// The inputs in the order they are processed, with the patterns expanded. There are
+    /// none when serving, the transactions arrive over the connections instead.
+    pub(super) fn inputs(&self) -> io::Result<Vec<Input>> {
+        if self.serve.is_some() {
+            return Ok(Vec::new());
+        }
+        if self.inputs.is_empty() {
+            return Ok(vec![Input::Stdin]);
+        }
+        let mut paths = Vec::new();
+        for input in &self.inputs {
+            if input == STDIN {
+                paths.push(Input::Stdin);
+                continue;
+            }
+            // Names without any special characters are taken as they are, so that a missing
+            // file is reported when it is opened.
+            if !input.contains(['*', '?', '[']) {
+                paths.push(Input::File(PathBuf::from(input)));
+                continue;
+            }
+            let matches = glob::glob(input)
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?
+                .collect::<Result<Vec<_>, glob::GlobError>>()?;
+            if matches.is_empty() {
+                return Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("no input files match {input}"),
+                ));
+            }
+            paths.extend(matches.into_iter().map(Input::File));
+        }
+        Ok(paths)
+    }
+
+    pub(super) fn backend(&self) -> io::Result<Backend> {
+        match &self.deposit_cache_file {
+            Some(path) => Ok(Backend::OnDisk {
+                store: Arc::new(on_disk::Store::create(path)?),
+            }),
+            None => Ok(Backend::InMemory {
+                pruning_strategy: self.pruning_strategy(),
+            }),
+        }
+    }
+
+    pub(super) fn withdrawal_dispute_policy(&self) -> WithdrawalDisputePolicy {
+        match self.withdrawal_disputes {
+            WithdrawalDisputes::Reject => WithdrawalDisputePolicy::Reject,
+            WithdrawalDisputes::Hold => WithdrawalDisputePolicy::Hold,
+            WithdrawalDisputes::NoHold => WithdrawalDisputePolicy::NoHold,
+        }
+    }
+
+    pub(super) fn precision(&self) -> Precision {
+        let excess = match self.excess_decimal_places {
+            ExcessDecimalPlaces::Reject => ExcessPrecision::Reject,
+            ExcessDecimalPlaces::RoundHalfEven => ExcessPrecision::RoundHalfEven,
+            ExcessDecimalPlaces::RoundHalfUp => ExcessPrecision::RoundHalfUp,
+            ExcessDecimalPlaces::Truncate => ExcessPrecision::Truncate,
+        };
+        Precision::new(self.decimal_places.into(), excess).expect("range checked by the parser")
+    }
+
+    fn pruning_strategy(&self) -> Option<PruningStrategy> {
+        match (self.prune_after, self.max_cached_deposits) {
+            (Some(seconds), _) => Some(PruningStrategy::Ttl {
+                duration: Duration::from_secs(seconds),
+            }),
+            (None, Some(max_size)) => Some(PruningStrategy::Size { max_size }),
+            (None, None) => None,
+        }
+    }
+}
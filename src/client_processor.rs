@@ -2,33 +2,46 @@
 //!
 //! It does not process the `total` balance as it can always be derived from `held` and `available`.
 
-use std::{
-    collections::HashMap,
-    sync::{
-        Arc,
-        atomic::{AtomicUsize, Ordering},
-    },
-};
-
-use tokio::sync::{mpsc, oneshot};
+use std::collections::{BTreeMap, HashMap};
 
 use crate::{
-    Balances, NonZero,
-    db::DepositValueCache,
+    Balances, balances,
+    currency::Currency,
+    db::{self, Backend, Cache, DepositValueCache, Disputable, in_mem::AmountCache},
     error::Error,
+    snapshot::{BalancesSnapshot, CachedSnapshot, ClientSnapshot},
     transaction::{
-        Chargeback, Deposit, Dispute, Resolve, Transaction, TransactionPayload, Withdrawal,
+        Chargeback, Credit, Deposit, Dispute, Resolve, Transaction, TransactionPayload, Transfer,
+        Unlock, Withdrawal,
     },
 };
 
+/// Decides whether withdrawals can be disputed, and what happens with the funds if they are.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum WithdrawalDisputePolicy {
+    /// Only deposits can be disputed. Withdrawals are not cached at all.
+    #[default]
+    Reject,
+    /// The disputed amount is held until the dispute is settled. On resolve the hold is
+    /// dropped and the withdrawal stands, on chargeback the amount becomes available again.
+    Hold,
+    /// Nothing is held while the dispute is open. On chargeback the amount is credited
+    /// back to the available funds.
+    NoHold,
+}
+
+/// Target of the log events which must be kept regardless of the configured log level.
+pub const AUDIT_TARGET: &str = "audit";
+
 pub(super) enum TransactionProcessingOutcome {
     LockAccount,
+    UnlockAccount,
     NoAction,
 }
 
 pub(super) trait TransactionProcessor<Database>
 where
-    Database: DepositValueCache<NonZero>,
+    Database: DepositValueCache<Disputable>,
 {
     fn process(
         self,
@@ -38,124 +51,251 @@ where
 
 impl<Database> TransactionProcessor<Database> for TransactionPayload<Deposit>
 where
-    Database: DepositValueCache<NonZero>,
+    Database: DepositValueCache<Disputable>,
 {
     fn process(
         self,
         processor: &mut ClientProcessor<Database>,
     ) -> Result<TransactionProcessingOutcome, Error> {
-        let amount = self.amount();
         let id = self.tx();
+        processor.ensure_unused(id)?;
         processor
-            .balances
-            .deposit(amount.into())
-            .map_err(|_| Error::InvalidTransaction { id })?;
-        processor
-            .db
-            .insert(self.tx(), self)
-            .map_err(|_| Error::DuplicatedTransaction { id })?;
+            .balances_mut(self.currency())
+            .deposit(self.amount().into())?;
+        processor.cache(id, Disputable::from(&self))?;
         Ok(TransactionProcessingOutcome::NoAction)
     }
 }
 
 impl<Database> TransactionProcessor<Database> for TransactionPayload<Withdrawal>
 where
-    Database: DepositValueCache<NonZero>,
+    Database: DepositValueCache<Disputable>,
 {
     fn process(
         self,
         processor: &mut ClientProcessor<Database>,
     ) -> Result<TransactionProcessingOutcome, Error> {
-        let amount = self.amount();
-        processor.balances.withdrawal(amount.into())?;
+        if processor.withdrawal_dispute_policy == WithdrawalDisputePolicy::Reject {
+            processor
+                .balances_to_debit(self.currency())?
+                .withdrawal(self.amount().into())?;
+            return Ok(TransactionProcessingOutcome::NoAction);
+        }
+        let id = self.tx();
+        processor.ensure_unused(id)?;
+        processor
+            .balances_to_debit(self.currency())?
+            .withdrawal(self.amount().into())?;
+        processor.cache(id, Disputable::from(&self))?;
         Ok(TransactionProcessingOutcome::NoAction)
     }
 }
 
 impl<Database> TransactionProcessor<Database> for TransactionPayload<Dispute>
 where
-    Database: DepositValueCache<NonZero>,
+    Database: DepositValueCache<Disputable>,
 {
     fn process(
         self,
         processor: &mut ClientProcessor<Database>,
     ) -> Result<TransactionProcessingOutcome, Error> {
-        if processor.disputed.contains_key(&self.tx()) {
-            return Ok(TransactionProcessingOutcome::NoAction);
+        let id = self.tx();
+        if processor.disputed.contains_key(&id) {
+            return Err(Error::AlreadyDisputed { id });
         }
-        if let Some(amount) = processor.db.get(&self.tx()) {
-            processor.balances.dispute(amount.into())?;
-            // TODO: Attack vector. One could try to dispute millions of transactions
-            // and never submit `resolve` or `chargeback`, trying to grow this map
-            // indefinitely. We should probably limit the amount of simultaneous disputes.
-            processor.disputed.insert(self.tx(), *amount);
+        let Some(disputed) = processor.db.get(&id)? else {
+            if processor.db.is_pruned(&id) {
+                return Err(Error::PrunedTransaction { id });
+            }
+            return Err(Error::UnknownTransaction { id });
         };
+        let policy = processor.withdrawal_dispute_policy;
+        let balances = processor.balances_mut(disputed.currency());
+        match disputed {
+            Disputable::Deposit { amount, .. } => balances.dispute(amount.into())?,
+            Disputable::Withdrawal { amount, .. } => match policy {
+                WithdrawalDisputePolicy::Reject => return Err(Error::UnknownTransaction { id }),
+                WithdrawalDisputePolicy::Hold => balances.dispute_withdrawal(amount.into())?,
+                WithdrawalDisputePolicy::NoHold => (),
+            },
+            Disputable::Transfer { .. } => return Err(Error::UnknownTransaction { id }),
+        }
+        // TODO: Attack vector. One could try to dispute millions of transactions
+        // and never submit `resolve` or `chargeback`, trying to grow this map
+        // indefinitely. We should probably limit the amount of simultaneous disputes.
+        processor.disputed.insert(id, disputed);
         Ok(TransactionProcessingOutcome::NoAction)
     }
 }
 
 impl<Database> TransactionProcessor<Database> for TransactionPayload<Resolve>
 where
-    Database: DepositValueCache<NonZero>,
+    Database: DepositValueCache<Disputable>,
 {
     fn process(
         self,
         processor: &mut ClientProcessor<Database>,
     ) -> Result<TransactionProcessingOutcome, Error> {
-        if let Some(amount) = processor.disputed.get(&self.tx()) {
-            processor.balances.resolve(amount.into())?;
-            processor.disputed.remove(&self.tx());
+        let id = self.tx();
+        let Some(disputed) = processor.disputed.get(&id).copied() else {
+            return Err(Error::NotDisputed { id });
         };
+        let policy = processor.withdrawal_dispute_policy;
+        let balances = processor.balances_mut(disputed.currency());
+        match (disputed, policy) {
+            (Disputable::Deposit { amount, .. }, _) => balances.resolve(amount.into())?,
+            (Disputable::Withdrawal { amount, .. }, WithdrawalDisputePolicy::Hold) => {
+                balances.resolve_withdrawal(amount.into())?
+            }
+            (Disputable::Withdrawal { .. } | Disputable::Transfer { .. }, _) => (),
+        }
+        processor.disputed.remove(&id);
         Ok(TransactionProcessingOutcome::NoAction)
     }
 }
 
 impl<Database> TransactionProcessor<Database> for TransactionPayload<Chargeback>
 where
-    Database: DepositValueCache<NonZero>,
+    Database: DepositValueCache<Disputable>,
 {
     fn process(
         self,
         processor: &mut ClientProcessor<Database>,
     ) -> Result<TransactionProcessingOutcome, Error> {
-        if let Some(amount) = processor.disputed.get(&self.tx()) {
-            processor.balances.chargeback(amount.into())?;
-            processor.disputed.remove(&self.tx());
-            return Ok(TransactionProcessingOutcome::LockAccount);
+        let id = self.tx();
+        let Some(disputed) = processor.disputed.get(&id).copied() else {
+            return Err(Error::NotDisputed { id });
         };
+        let policy = processor.withdrawal_dispute_policy;
+        let balances = processor.balances_mut(disputed.currency());
+        match (disputed, policy) {
+            (Disputable::Deposit { amount, .. }, _) => balances.chargeback(amount.into())?,
+            (Disputable::Withdrawal { amount, .. }, WithdrawalDisputePolicy::Hold) => {
+                balances.chargeback_withdrawal(amount.into())?
+            }
+            (Disputable::Withdrawal { amount, .. }, _) => balances.deposit(amount.into())?,
+            // Never disputed in the first place.
+            (Disputable::Transfer { .. }, _) => (),
+        }
+        processor.disputed.remove(&id);
+        Ok(TransactionProcessingOutcome::LockAccount)
+    }
+}
+
+// Transfers can not be disputed, but both of their sides are cached, so that the ID is
+// reserved for the transfer at both clients.
+impl<Database> TransactionProcessor<Database> for TransactionPayload<Transfer>
+where
+    Database: DepositValueCache<Disputable>,
+{
+    fn process(
+        self,
+        processor: &mut ClientProcessor<Database>,
+    ) -> Result<TransactionProcessingOutcome, Error> {
+        let id = self.tx();
+        processor.ensure_unused(id)?;
+        processor
+            .balances_to_debit(self.currency())?
+            .withdrawal(self.amount().into())?;
+        processor.cache(id, Disputable::from(&self))?;
         Ok(TransactionProcessingOutcome::NoAction)
     }
 }
 
+impl<Database> TransactionProcessor<Database> for TransactionPayload<Credit>
+where
+    Database: DepositValueCache<Disputable>,
+{
+    fn process(
+        self,
+        processor: &mut ClientProcessor<Database>,
+    ) -> Result<TransactionProcessingOutcome, Error> {
+        let id = self.tx();
+        if self.is_refund() {
+            // The transfer did not happen, so its ID is released again.
+            processor.db.remove(id)?;
+        } else {
+            processor.ensure_unused(id)?;
+        }
+        processor
+            .balances_mut(self.currency())
+            .deposit(self.amount().into())?;
+        if !self.is_refund() {
+            processor.cache(id, Disputable::from(&self))?;
+        }
+        Ok(TransactionProcessingOutcome::NoAction)
+    }
+}
+
+impl<Database> TransactionProcessor<Database> for TransactionPayload<Unlock>
+where
+    Database: DepositValueCache<Disputable>,
+{
+    fn process(
+        self,
+        processor: &mut ClientProcessor<Database>,
+    ) -> Result<TransactionProcessingOutcome, Error> {
+        if !processor.locked {
+            return Err(Error::AccountNotLocked {
+                client: processor.client,
+            });
+        }
+        // Reopening an account is a compliance decision, so it is always logged. The spans
+        // may be filtered out, hence the client and transaction are repeated here.
+        tracing::info!(
+            target: AUDIT_TARGET,
+            client = processor.client,
+            tx = self.tx(),
+            authorised_by = self.authorised_by(),
+            "account unlocked"
+        );
+        Ok(TransactionProcessingOutcome::UnlockAccount)
+    }
+}
+
 /// Represents the final client state after all transactions have been processed.
-pub(super) struct ClientState {
+pub struct ClientState {
     client: u16,
     locked: bool,
-    balances: Balances,
+    balances: BTreeMap<Option<Currency>, Balances>,
+    // Number of disputes neither resolved nor charged back, by the currency of the amount.
+    open_disputes: BTreeMap<Option<Currency>, usize>,
 }
 
 impl ClientState {
-    pub(super) fn balances(&self) -> &Balances {
-        &self.balances
+    /// Balances of every currency the client has used, ordered by the currency.
+    pub fn balances(&self) -> impl Iterator<Item = (Option<Currency>, &Balances)> {
+        self.balances
+            .iter()
+            .map(|(currency, balances)| (*currency, balances))
     }
 
-    pub(super) fn client(&self) -> u16 {
+    pub fn client(&self) -> u16 {
         self.client
     }
 
-    pub(super) fn locked(&self) -> bool {
+    pub fn locked(&self) -> bool {
         self.locked
     }
+
+    /// Number of open disputes of the amounts in the given currency.
+    pub fn open_disputes(&self, currency: Option<Currency>) -> usize {
+        self.open_disputes
+            .get(&currency)
+            .copied()
+            .unwrap_or_default()
+    }
 }
 
 pub(super) struct ClientProcessor<Database>
 where
-    Database: DepositValueCache<NonZero>,
+    Database: DepositValueCache<Disputable>,
 {
     // Client ID
     client: u16,
-    // Each client takes care of its own balance.
-    balances: Balances,
+    // Each client takes care of its own balances, one for every currency it uses.
+    // Without currencies in the input, there is a single balance under `None`.
+    balances: BTreeMap<Option<Currency>, Balances>,
     // The account is locked if there was a chargeback.
     locked: bool,
     // Abstracted database. It could be anything that can store and retrieve
@@ -165,34 +305,70 @@ where
     // The map of amounts being disputed. It is not abstracted due to the
     // assumption that there will be a limited number of active disputes
     // compared to the total number of transactions.
-    disputed: HashMap<u32, NonZero>,
-    // The channel to receive transactions from the stream processor.
-    tx_receiver: mpsc::Receiver<Transaction>,
-    // The channel to send the result back to the stream processor.
-    result_sender: Option<oneshot::Sender<ClientState>>,
+    disputed: HashMap<u32, Disputable>,
+    // Decides whether withdrawals are cached and how their disputes are handled.
+    withdrawal_dispute_policy: WithdrawalDisputePolicy,
+    // Parent span of all the transactions of this client.
+    span: tracing::Span,
 }
 
 impl<Database> ClientProcessor<Database>
 where
-    Database: DepositValueCache<NonZero>,
+    Database: DepositValueCache<Disputable>,
 {
     pub(super) fn new(
         client: u16,
         db: Database,
-        tx_receiver: mpsc::Receiver<Transaction>,
-        result_sender: oneshot::Sender<ClientState>,
+        withdrawal_dispute_policy: WithdrawalDisputePolicy,
     ) -> Self {
         Self {
             client,
-            balances: Balances::new(),
+            balances: BTreeMap::new(),
             disputed: HashMap::new(),
+            withdrawal_dispute_policy,
             db,
             locked: false,
-            tx_receiver,
-            result_sender: Some(result_sender),
+            span: tracing::info_span!("client", client),
         }
     }
 
+    pub(super) fn client(&self) -> u16 {
+        self.client
+    }
+
+    pub(super) fn span(&self) -> &tracing::Span {
+        &self.span
+    }
+
+    // Balances are created when the currency is used for the first time.
+    fn balances_mut(&mut self, currency: Option<Currency>) -> &mut Balances {
+        self.balances.entry(currency).or_insert_with(Balances::new)
+    }
+
+    // IDs of the cached transactions can not be reused, not even after they were pruned.
+    fn ensure_unused(&self, id: u32) -> Result<(), Error> {
+        if self.db.get(&id)?.is_some() || self.db.is_pruned(&id) {
+            return Err(Error::DuplicatedTransaction { id });
+        }
+        Ok(())
+    }
+
+    // Balances to withdraw from. A currency the client never had has no funds, so unlike
+    // in `balances_mut` no balances are created for it.
+    fn balances_to_debit(&mut self, currency: Option<Currency>) -> Result<&mut Balances, Error> {
+        self.balances
+            .get_mut(&currency)
+            .ok_or(Error::Balances(balances::Error::InsufficientFunds))
+    }
+
+    // Remembers the transaction so that it can be disputed later.
+    fn cache(&mut self, id: u32, value: Disputable) -> Result<(), Error> {
+        self.db.insert(id, value).map_err(|err| match err {
+            db::Error::AlreadyExists => Error::DuplicatedTransaction { id },
+            err => Error::Database(err),
+        })
+    }
+
     fn process<Kind>(
         &mut self,
         tx: TransactionPayload<Kind>,
@@ -203,43 +379,139 @@ where
         tx.process(self)
     }
 
-    pub(super) async fn crank(&mut self, tx_counter: Arc<AtomicUsize>) -> Result<(), Error> {
-        while let Some(tx) = self.tx_receiver.recv().await {
-            if !self.locked {
-                let tx_process_result = match tx {
-                    Transaction::Deposit(tx) => self.process(tx),
-                    Transaction::Withdrawal(tx) => self.process(tx),
-                    Transaction::Dispute(tx) => self.process(tx),
-                    Transaction::Resolve(tx) => self.process(tx),
-                    Transaction::Chargeback(tx) => self.process(tx),
-                };
-                match tx_process_result {
-                    Ok(outcome) => {
-                        if let TransactionProcessingOutcome::LockAccount = outcome {
-                            self.locked = true;
-                        }
-                    }
-                    Err(_e) => {
-                        // tracing::error!("Error processing transaction: {:?}", _e);
-                    }
-                }
+    /// Applies a single transaction of this client. Transactions of a locked account are rejected,
+    /// except for the unlock itself.
+    pub(super) fn handle(&mut self, tx: Transaction) -> Result<(), Error> {
+        if self.locked && !matches!(tx, Transaction::Unlock(_)) {
+            return Err(Error::AccountLocked {
+                client: self.client,
+            });
+        }
+        let outcome = match tx {
+            Transaction::Deposit(tx) => self.process(tx),
+            Transaction::Withdrawal(tx) => self.process(tx),
+            Transaction::Dispute(tx) => self.process(tx),
+            Transaction::Resolve(tx) => self.process(tx),
+            Transaction::Chargeback(tx) => self.process(tx),
+            Transaction::Transfer(tx) => self.process(tx),
+            Transaction::Credit(tx) => self.process(tx),
+            Transaction::Unlock(tx) => self.process(*tx),
+        }?;
+        match outcome {
+            TransactionProcessingOutcome::LockAccount => {
+                tracing::info!("account locked");
+                self.locked = true;
             }
-            tx_counter.fetch_sub(1, Ordering::SeqCst);
+            TransactionProcessingOutcome::UnlockAccount => self.locked = false,
+            TransactionProcessingOutcome::NoAction => (),
         }
+        Ok(())
+    }
 
-        if let Some(sender) = self.result_sender.take() {
-            sender
-                .send(ClientState {
-                    client: self.client,
-                    locked: self.locked,
-                    balances: self.balances.clone(),
-                })
-                .unwrap_or(
-                    // tracing::error!("failed to send result for client {}", self.client);
-                    (),
-                );
+    /// Currency whose balances the transaction affects. For disputes, resolves and
+    /// chargebacks it is the currency of the original transaction, if that is known.
+    pub(super) fn currency_of(&self, tx: &Transaction) -> Option<Currency> {
+        let disputed = |id| {
+            self.disputed
+                .get(&id)
+                .copied()
+                .or_else(|| self.db.get(&id).ok().flatten())
+                .and_then(|disputed| disputed.currency())
+        };
+        match tx {
+            Transaction::Deposit(tx) => tx.currency(),
+            Transaction::Withdrawal(tx) => tx.currency(),
+            Transaction::Dispute(tx) => disputed(tx.tx()),
+            Transaction::Resolve(tx) => disputed(tx.tx()),
+            Transaction::Chargeback(tx) => disputed(tx.tx()),
+            Transaction::Transfer(tx) => tx.currency(),
+            Transaction::Credit(tx) => tx.currency(),
+            Transaction::Unlock(tx) => tx.currency(),
         }
+    }
 
-        Ok(())
+    /// Balances of the currency, zero if it has not been used yet.
+    pub(super) fn balances(&self, currency: Option<Currency>) -> Balances {
+        self.balances
+            .get(&currency)
+            .cloned()
+            .unwrap_or_else(Balances::new)
+    }
+
+    pub(super) fn locked(&self) -> bool {
+        self.locked
+    }
+
+    pub(super) fn state(&self) -> ClientState {
+        let mut open_disputes = BTreeMap::new();
+        for disputed in self.disputed.values() {
+            *open_disputes.entry(disputed.currency()).or_default() += 1;
+        }
+        ClientState {
+            client: self.client,
+            locked: self.locked,
+            balances: self.balances.clone(),
+            open_disputes,
+        }
+    }
+}
+
+impl ClientProcessor<Cache> {
+    /// Serializable state of the client. `None` unless the cached transactions are kept in
+    /// memory, others can not be captured at a single point in time.
+    pub(super) fn snapshot(&self) -> Option<ClientSnapshot> {
+        let Cache::InMemory(cache) = &self.db else {
+            return None;
+        };
+        let (cached, pruned) = cache.snapshot();
+        let mut disputed: Vec<_> = self
+            .disputed
+            .iter()
+            .map(|(id, value)| CachedSnapshot::new(*id, *value, None))
+            .collect();
+        disputed.sort_by_key(|disputed| disputed.tx);
+        Some(ClientSnapshot {
+            client: self.client,
+            locked: self.locked,
+            balances: self
+                .balances
+                .iter()
+                .map(|(currency, balances)| BalancesSnapshot::new(*currency, balances))
+                .collect(),
+            disputed,
+            cached,
+            pruned,
+        })
+    }
+
+    /// Recreates the client from a snapshot. `None` unless the backend keeps the
+    /// transactions in memory, others are not part of the snapshots.
+    pub(super) fn restore(
+        snapshot: &ClientSnapshot,
+        backend: &Backend,
+        withdrawal_dispute_policy: WithdrawalDisputePolicy,
+    ) -> Option<Self> {
+        let Backend::InMemory { pruning_strategy } = backend else {
+            return None;
+        };
+        let cache =
+            AmountCache::restore(pruning_strategy.clone(), &snapshot.cached, &snapshot.pruned);
+        let mut processor = Self::new(
+            snapshot.client,
+            Cache::InMemory(cache),
+            withdrawal_dispute_policy,
+        );
+        processor.locked = snapshot.locked;
+        processor.balances = snapshot
+            .balances
+            .iter()
+            .map(BalancesSnapshot::restore)
+            .collect();
+        processor.disputed = snapshot
+            .disputed
+            .iter()
+            .map(|disputed| (disputed.tx, disputed.value()))
+            .collect();
+        Some(processor)
     }
 }
@@ -0,0 +1,137 @@
+//! Transparent decompression of the inputs.
+//!
+//! The compression is recognised by the magic bytes at the start of the input rather than by
+//! the file extension, so that compressed data piped through `stdin` is handled too.
+
+use std::io;
+
+use async_compression::tokio::bufread::{GzipDecoder, ZstdDecoder};
+use tokio::io::{AsyncRead, AsyncReadExt, BufReader};
+
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+const ZSTD_MAGIC: &[u8] = &[0x28, 0xb5, 0x2f, 0xfd];
+
+/// Wraps the reader in a decoder if the data is gzip or zstd compressed, otherwise the data
+/// is passed through as it is.
+pub(super) async fn decompressed<R>(mut reader: R) -> io::Result<Box<dyn AsyncRead + Unpin + Send>>
+where
+    R: AsyncRead + Unpin + Send + 'static,
+{
+    // A single read may return fewer bytes than the magic, e.g. from a pipe, so the reads
+    // go on until the longest magic is in or the data ends. What was read is put back.
+    let mut head = Vec::with_capacity(ZSTD_MAGIC.len());
+    while head.len() < ZSTD_MAGIC.len() {
+        let mut buf = [0; ZSTD_MAGIC.len()];
+        let n = reader
+            .read(&mut buf[..ZSTD_MAGIC.len() - head.len()])
+            .await?;
+        if n == 0 {
+            break;
+        }
+        head.extend_from_slice(&buf[..n]);
+    }
+    let is_gzip = head.starts_with(GZIP_MAGIC);
+    let is_zstd = head.starts_with(ZSTD_MAGIC);
+    let reader = io::Cursor::new(head).chain(BufReader::new(reader));
+    if is_gzip {
+        let mut decoder = GzipDecoder::new(reader);
+        // Concatenated archives, e.g. produced by appending to a `.gz` file, are common.
+        decoder.multiple_members(true);
+        Ok(Box::new(decoder))
+    } else if is_zstd {
+        let mut decoder = ZstdDecoder::new(reader);
+        decoder.multiple_members(true);
+        Ok(Box::new(decoder))
+    } else {
+        Ok(Box::new(reader))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::Cursor,
+        pin::Pin,
+        task::{Context, Poll},
+    };
+
+    use async_compression::tokio::write::{GzipEncoder, ZstdEncoder};
+    use test_case::test_case;
+    use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+
+    use super::decompressed;
+
+    const CSV: &str = "type,client,tx,amount\ndeposit,1,1,1.5\n";
+
+    async fn compress<W: AsyncWrite + Unpin>(mut encoder: W) -> W {
+        encoder.write_all(CSV.as_bytes()).await.unwrap();
+        encoder.shutdown().await.unwrap();
+        encoder
+    }
+
+    // Returns a single byte per read, like a slow pipe.
+    struct Trickle(Cursor<Vec<u8>>);
+
+    impl AsyncRead for Trickle {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &mut ReadBuf<'_>,
+        ) -> Poll<std::io::Result<()>> {
+            let mut byte = [0; 1];
+            let mut one = ReadBuf::new(&mut byte);
+            let poll = Pin::new(&mut self.0).poll_read(cx, &mut one);
+            buf.put_slice(one.filled());
+            poll
+        }
+    }
+
+    async fn decompress(data: Vec<u8>) -> String {
+        read_to_string(decompressed(Cursor::new(data)).await.unwrap()).await
+    }
+
+    async fn read_to_string<R: AsyncRead + Unpin>(mut reader: R) -> String {
+        let mut text = String::new();
+        reader.read_to_string(&mut text).await.unwrap();
+        text
+    }
+
+    #[test_case(false ; "plain")]
+    #[test_case(true ; "empty")]
+    #[tokio::test]
+    async fn plain(empty: bool) {
+        let data = if empty { "" } else { CSV };
+        assert_eq!(decompress(data.as_bytes().to_vec()).await, data);
+    }
+
+    #[tokio::test]
+    async fn gzip() {
+        let data = compress(GzipEncoder::new(Vec::new())).await.into_inner();
+        assert_eq!(decompress(data).await, CSV);
+    }
+
+    #[tokio::test]
+    async fn concatenated_gzip() {
+        let mut data = compress(GzipEncoder::new(Vec::new())).await.into_inner();
+        data.extend(compress(GzipEncoder::new(Vec::new())).await.into_inner());
+        assert_eq!(decompress(data).await, CSV.repeat(2));
+    }
+
+    #[tokio::test]
+    async fn zstd() {
+        let data = compress(ZstdEncoder::new(Vec::new())).await.into_inner();
+        assert_eq!(decompress(data).await, CSV);
+    }
+
+    #[test_case(false ; "plain")]
+    #[test_case(true ; "zstd")]
+    #[tokio::test]
+    async fn short_reads(compressed: bool) {
+        let data = match compressed {
+            true => compress(ZstdEncoder::new(Vec::new())).await.into_inner(),
+            false => CSV.as_bytes().to_vec(),
+        };
+        let reader = decompressed(Trickle(Cursor::new(data))).await.unwrap();
+        assert_eq!(read_to_string(reader).await, CSV);
+    }
+}
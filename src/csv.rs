@@ -1,16 +1,26 @@
-use serde::{Deserialize, Serialize};
+//! Records read from the CSV input and written to the CSV output.
+
+use std::sync::{Arc, OnceLock};
+
+use csv_async::AsyncDeserializer;
+use futures_util::{Stream, StreamExt, io::AsyncRead};
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
 
 use crate::{
-    BalanceUpdater, NonNegative, NonZero,
+    Balances, NonZero, ReadError,
+    amount::{Amount, Fixed, InputAmount, ParseAmountError, Precision},
     client_processor::ClientState,
+    currency::Currency,
     transaction::{
-        Chargeback, Deposit, Dispute, Resolve, Transaction, TransactionPayload, Withdrawal,
+        Chargeback, Deposit, Dispute, Resolve, Transaction, TransactionPayload, Transfer, Unlock,
+        Withdrawal,
     },
 };
 
 #[allow(clippy::enum_variant_names)]
 #[derive(Debug, thiserror::Error)]
-pub(super) enum Error {
+#[non_exhaustive]
+pub enum Error {
     #[error("deposit must have an amount")]
     DepositMustHaveAmount,
     #[error("deposit must have a non-zero amount")]
@@ -19,35 +29,289 @@ pub(super) enum Error {
     WithdrawalMustHaveAmount,
     #[error("withdrawal must have a non-zero amount")]
     WithdrawalMustHaveNonZeroAmount,
+    #[error("amount has more decimal places than allowed")]
+    AmountHasTooManyDecimalPlaces,
+    #[error("amount is out of the supported range")]
+    AmountOutOfRange,
+    #[error("invalid amount")]
+    InvalidAmount,
+    #[error("amount must have a currency")]
+    AmountMustHaveCurrency,
+    #[error("amount must not have a currency")]
+    AmountMustNotHaveCurrency,
+    #[error("transfer must have an amount")]
+    TransferMustHaveAmount,
+    #[error("transfer must have a non-zero amount")]
+    TransferMustHaveNonZeroAmount,
+    #[error("transfer must have a destination")]
+    TransferMustHaveDestination,
+    #[error("transfer must have a destination other than the source")]
+    TransferToSameClient,
+    #[error("unlock must be authorised")]
+    UnlockMustBeAuthorised,
+}
+
+/// Client states which can not be written to the output.
+#[derive(Debug, thiserror::Error)]
+#[non_exhaustive]
+pub enum OutputError {
+    #[error("total balance of client {client} overflows")]
+    TotalBalanceOverflow { client: u16 },
 }
 
-// Transaction as created from the CSV input. This metadata is converted
-// to a correct transaction before being processed.
+/// Transaction as created from the CSV input. This metadata is converted
+/// to a correct transaction before being processed.
 #[derive(Clone, Debug, Deserialize)]
-pub(super) struct InputRecord<MonetaryValue> {
+pub struct InputRecord<MonetaryValue> {
     #[serde(rename = "type", deserialize_with = "Kind::from_deserializer")]
     kind: Kind,
     client: u16,
     tx: u32,
     amount: Option<MonetaryValue>,
+    // `None` if the input has no currency column, all amounts are then in a single unnamed
+    // currency. Otherwise every amount must name its currency.
+    #[serde(default, deserialize_with = "currency_column")]
+    currency: Option<Option<Currency>>,
+    // Only used by transfers. The column may be missing from the input altogether.
+    destination: Option<u16>,
+    // Only used by unlocks. The column may be missing from the input altogether.
+    authorised_by: Option<String>,
+    // Set when the currency was named although the previous inputs had none.
+    #[serde(skip)]
+    unexpected_currency: bool,
+    // Line of the input file the record was read from, used for error reporting.
+    #[serde(skip)]
+    line: u64,
 }
 
-impl<MonetaryValue> TryFrom<InputRecord<MonetaryValue>> for Transaction
+impl<MonetaryValue> InputRecord<MonetaryValue> {
+    /// Creates a record in code, e.g. when the engine is embedded and the transactions do
+    /// not come from a file. The optional fields are set with the `with_*` methods.
+    ///
+    /// ```
+    /// use futures_util::{StreamExt, stream};
+    /// use tx_processor::{InputAmount, StreamProcessor, csv::{InputRecord, Kind}};
+    ///
+    /// # #[tokio::main]
+    /// # async fn main() {
+    /// let records = vec![
+    ///     InputRecord::new(Kind::Deposit, 1, 1).with_amount("1.5".parse::<InputAmount>().unwrap()),
+    ///     InputRecord::new(Kind::Dispute, 1, 1),
+    /// ];
+    /// let mut processor = StreamProcessor::new();
+    /// let states: Vec<_> = processor
+    ///     .process(stream::iter(records.into_iter().map(Ok)))
+    ///     .await
+    ///     .collect()
+    ///     .await;
+    /// let (_, balances) = states[0].as_ref().unwrap().balances().next().unwrap();
+    /// assert_eq!(balances.held().fixed(1).to_string(), "1.5");
+    /// # }
+    /// ```
+    pub fn new(kind: Kind, client: u16, tx: u32) -> Self {
+        Self {
+            kind,
+            client,
+            tx,
+            amount: None,
+            currency: None,
+            destination: None,
+            authorised_by: None,
+            unexpected_currency: false,
+            line: 0,
+        }
+    }
+
+    pub fn with_amount(self, amount: MonetaryValue) -> Self {
+        Self {
+            amount: Some(amount),
+            ..self
+        }
+    }
+
+    /// Names the currency of the amount. Either all records with an amount name their
+    /// currency, or none of them.
+    pub fn with_currency(self, currency: Currency) -> Self {
+        Self {
+            currency: Some(Some(currency)),
+            ..self
+        }
+    }
+
+    /// The client receiving the funds of a transfer.
+    pub fn with_destination(self, destination: u16) -> Self {
+        Self {
+            destination: Some(destination),
+            ..self
+        }
+    }
+
+    /// Who allowed an unlock.
+    pub fn with_authorised_by(self, authorised_by: String) -> Self {
+        Self {
+            authorised_by: Some(authorised_by),
+            ..self
+        }
+    }
+
+    pub fn kind(&self) -> Kind {
+        self.kind
+    }
+
+    pub fn client(&self) -> u16 {
+        self.client
+    }
+
+    pub fn tx(&self) -> u32 {
+        self.tx
+    }
+
+    pub fn line(&self) -> u64 {
+        self.line
+    }
+
+    #[cfg(test)]
+    pub(crate) fn amount(&self) -> Option<&MonetaryValue> {
+        self.amount.as_ref()
+    }
+
+    /// Line of the input the record was read from, reported with the rejections and the
+    /// outcomes.
+    pub fn with_line(self, line: u64) -> Self {
+        Self { line, ..self }
+    }
+
+    // Lets the input formats read the amounts with their own types.
+    pub(crate) fn map_amount<M>(self, f: impl FnOnce(MonetaryValue) -> M) -> InputRecord<M> {
+        InputRecord {
+            kind: self.kind,
+            client: self.client,
+            tx: self.tx,
+            amount: self.amount.map(f),
+            currency: self.currency,
+            destination: self.destination,
+            authorised_by: self.authorised_by,
+            unexpected_currency: self.unexpected_currency,
+            line: self.line,
+        }
+    }
+
+    // Inputs without a header, e.g. JSON Lines, decide on the currency column per record.
+    // A record disagreeing with the decision is rejected when it is converted.
+    pub(crate) fn agree_on_currency(mut self, column: &CurrencyColumn) -> Self {
+        if self.amount.is_some() && !column.agrees(self.currency.is_some()) {
+            match self.currency {
+                None => self.currency = Some(None),
+                Some(_) => self.unexpected_currency = true,
+            }
+        }
+        self
+    }
+}
+
+fn currency_column<'de, D>(deserializer: D) -> Result<Option<Option<Currency>>, D::Error>
 where
-    MonetaryValue: TryInto<NonZero>,
+    D: serde::Deserializer<'de>,
 {
-    type Error = Error;
+    Option::<Currency>::deserialize(deserializer).map(Some)
+}
 
-    fn try_from(value: InputRecord<MonetaryValue>) -> Result<Self, Self::Error> {
+/// Whether the amounts of the inputs name their currencies. Decided by the first input,
+/// the CSV header or the first JSON record with an amount, and shared by the clones, so
+/// that all inputs agree on it.
+#[derive(Debug, Clone, Default)]
+pub struct CurrencyColumn(Arc<OnceLock<bool>>);
+
+impl CurrencyColumn {
+    /// Decides on `present` unless already decided, and tells whether it matches the
+    /// decision.
+    pub fn agrees(&self, present: bool) -> bool {
+        *self.0.get_or_init(|| present) == present
+    }
+
+    /// `None` until decided.
+    pub fn present(&self) -> Option<bool> {
+        self.0.get().copied()
+    }
+}
+
+/// Tells whether the input names the currencies of the amounts, in which case the output
+/// needs a currency column too.
+pub async fn has_currency_column<R>(
+    reader: &mut AsyncDeserializer<R>,
+) -> Result<bool, csv_async::Error>
+where
+    R: AsyncRead + Unpin + Send,
+{
+    Ok(reader
+        .headers()
+        .await?
+        .iter()
+        .any(|header| header == "currency"))
+}
+
+/// Deserializes the input records, remembering the line each of them was read from.
+pub fn records<R, MonetaryValue>(
+    reader: &mut AsyncDeserializer<R>,
+) -> impl Stream<Item = Result<InputRecord<MonetaryValue>, ReadError>> + Unpin + '_
+where
+    R: AsyncRead + Unpin + Send,
+    MonetaryValue: DeserializeOwned + 'static,
+{
+    reader
+        .deserialize_with_pos::<InputRecord<MonetaryValue>>()
+        .map(|(record, position)| {
+            record
+                .map(|record| record.with_line(position.line()))
+                .map_err(ReadError::from)
+        })
+}
+
+impl From<ParseAmountError> for Error {
+    fn from(err: ParseAmountError) -> Self {
+        match err {
+            ParseAmountError::TooManyDecimalPlaces => Self::AmountHasTooManyDecimalPlaces,
+            ParseAmountError::OutOfRange => Self::AmountOutOfRange,
+            ParseAmountError::Invalid => Self::InvalidAmount,
+        }
+    }
+}
+
+// Reads the amount with the given precision, `non_zero` is returned if it is not positive.
+fn non_zero_amount<MonetaryValue>(
+    value: MonetaryValue,
+    precision: Precision,
+    non_zero: Error,
+) -> Result<NonZero, Error>
+where
+    MonetaryValue: Into<InputAmount>,
+{
+    let amount = value.into().to_amount(precision)?;
+    NonZero::try_from(amount).map_err(|_| non_zero)
+}
+
+impl<MonetaryValue> InputRecord<MonetaryValue>
+where
+    MonetaryValue: Into<InputAmount>,
+{
+    /// Converts the record to a correct transaction, reading the amount with `precision`.
+    pub fn into_transaction(self, precision: Precision) -> Result<Transaction, Error> {
+        let value = self;
+        // Only checked for the transactions with an amount.
+        let currency = || match value.currency {
+            _ if value.unexpected_currency => Err(Error::AmountMustNotHaveCurrency),
+            Some(None) => Err(Error::AmountMustHaveCurrency),
+            Some(currency) => Ok(currency),
+            None => Ok(None),
+        };
         match value.kind {
             Kind::Deposit => {
                 let amount = value.amount.ok_or(Error::DepositMustHaveAmount)?;
                 Ok(Transaction::Deposit(TransactionPayload::<Deposit>::new(
                     value.client,
                     value.tx,
-                    amount
-                        .try_into()
-                        .map_err(|_| Error::DepositMustHaveNonZeroAmount)?,
+                    non_zero_amount(amount, precision, Error::DepositMustHaveNonZeroAmount)?,
+                    currency()?,
                 )))
             }
             Kind::Withdrawal => {
@@ -56,9 +320,8 @@ where
                     TransactionPayload::<Withdrawal>::new(
                         value.client,
                         value.tx,
-                        amount
-                            .try_into()
-                            .map_err(|_| Error::WithdrawalMustHaveNonZeroAmount)?,
+                        non_zero_amount(amount, precision, Error::WithdrawalMustHaveNonZeroAmount)?,
+                        currency()?,
                     ),
                 ))
             }
@@ -73,52 +336,106 @@ where
             Kind::Chargeback => Ok(Transaction::Chargeback(
                 TransactionPayload::<Chargeback>::new(value.client, value.tx),
             )),
+            Kind::Transfer => {
+                let amount = value.amount.ok_or(Error::TransferMustHaveAmount)?;
+                let destination = value
+                    .destination
+                    .ok_or(Error::TransferMustHaveDestination)?;
+                if destination == value.client {
+                    return Err(Error::TransferToSameClient);
+                }
+                Ok(Transaction::Transfer(TransactionPayload::<Transfer>::new(
+                    value.client,
+                    value.tx,
+                    non_zero_amount(amount, precision, Error::TransferMustHaveNonZeroAmount)?,
+                    currency()?,
+                    destination,
+                )))
+            }
+            Kind::Unlock => {
+                let authorised_by = value.authorised_by.ok_or(Error::UnlockMustBeAuthorised)?;
+                Ok(Transaction::Unlock(Box::new(
+                    TransactionPayload::<Unlock>::new(value.client, value.tx, authorised_by),
+                )))
+            }
         }
     }
 }
 
-// This struct is used to serialize the results of processing.
+/// This struct is used to serialize the results of processing.
 #[derive(Debug, Serialize)]
-pub(super) struct OutputRecord {
+pub struct OutputRecord {
     client: u16,
-    available: NonNegative,
-    held: NonNegative,
-    total: NonNegative,
+    // Only present if the input names the currencies.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    currency: Option<Currency>,
+    available: Fixed,
+    held: Fixed,
+    total: Fixed,
     locked: bool,
 }
 
-impl TryFrom<ClientState> for OutputRecord {
-    type Error = anyhow::Error;
+impl OutputRecord {
+    /// Creates a record for every currency of the client. Without a currency column in the
+    /// input, there is exactly one record, even if the client never had any funds.
+    /// Amounts are written with exactly `decimal_places`.
+    pub fn from_client_state(
+        client_state: &ClientState,
+        with_currency: bool,
+        decimal_places: usize,
+    ) -> Result<Vec<Self>, OutputError> {
+        let empty = Balances::new();
+        let mut balances: Vec<_> = client_state.balances().collect();
+        if balances.is_empty() && !with_currency {
+            balances.push((None, &empty));
+        }
+        balances
+            .into_iter()
+            .map(|(currency, balances)| {
+                let total = balances.total().ok_or(OutputError::TotalBalanceOverflow {
+                    client: client_state.client(),
+                })?;
+                Ok(Self {
+                    client: client_state.client(),
+                    currency,
+                    available: balances.available().fixed(decimal_places),
+                    held: balances.held().fixed(decimal_places),
+                    total: total.fixed(decimal_places),
+                    locked: client_state.locked(),
+                })
+            })
+            .collect()
+    }
 
-    fn try_from(client_state: ClientState) -> Result<Self, Self::Error> {
-        let balances = client_state.balances();
-        let total = balances.available().add(balances.held());
-        let Some(total) = total else {
-            return Err(anyhow::anyhow!("total balance overflow"));
-        };
-        Ok(Self {
-            client: client_state.client(),
-            available: balances.available(),
-            held: balances.held(),
-            total,
-            locked: client_state.locked(),
-        })
+    pub fn client(&self) -> u16 {
+        self.client
+    }
+
+    pub fn currency(&self) -> Option<Currency> {
+        self.currency
+    }
+
+    pub fn total(&self) -> Amount {
+        self.total.amount()
     }
 }
 
-// Helper struct that deserializes the CSV input into the correct transaction type.
-// It helps to avoid carrying around the `String` instance with every transaction.
-#[derive(Debug, Copy, Clone)]
-pub(super) enum Kind {
+/// Helper struct that deserializes the CSV input into the correct transaction type.
+/// It helps to avoid carrying around the `String` instance with every transaction.
+#[derive(Debug, Copy, Clone, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Kind {
     Deposit,
     Withdrawal,
     Dispute,
     Resolve,
     Chargeback,
+    Transfer,
+    Unlock,
 }
 
 impl Kind {
-    pub(super) fn from_deserializer<'de, D>(deserializer: D) -> Result<Self, D::Error>
+    pub(crate) fn from_deserializer<'de, D>(deserializer: D) -> Result<Self, D::Error>
     where
         D: serde::Deserializer<'de>,
     {
@@ -131,6 +448,8 @@ impl Kind {
             "dispute" => Ok(Self::Dispute),
             "resolve" => Ok(Self::Resolve),
             "chargeback" => Ok(Self::Chargeback),
+            "transfer" => Ok(Self::Transfer),
+            "unlock" => Ok(Self::Unlock),
             _ => Err(serde::de::Error::custom(format!(
                 "Unknown transaction type: {}",
                 s
@@ -138,3 +457,18 @@ impl Kind {
         }
     }
 }
+
+impl From<&Transaction> for Kind {
+    fn from(tx: &Transaction) -> Self {
+        match tx {
+            Transaction::Deposit(_) => Self::Deposit,
+            Transaction::Withdrawal(_) => Self::Withdrawal,
+            Transaction::Dispute(_) => Self::Dispute,
+            Transaction::Resolve(_) => Self::Resolve,
+            Transaction::Chargeback(_) => Self::Chargeback,
+            // Both sides of a transfer are reported as the transfer itself.
+            Transaction::Transfer(_) | Transaction::Credit(_) => Self::Transfer,
+            Transaction::Unlock(_) => Self::Unlock,
+        }
+    }
+}
@@ -0,0 +1,102 @@
+//! Currency in which the monetary values are expressed.
+//!
+//! Codes are short, so they are kept inline instead of in a `String`. This keeps the
+//! transactions and the cached deposits `Copy` and allocation free.
+
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Currency([u8; Currency::BYTES]);
+
+impl Currency {
+    // Longer than ISO 4217 codes, so that also the common crypto tickers fit.
+    pub(crate) const BYTES: usize = 8;
+
+    pub(crate) fn to_bytes(self) -> [u8; Self::BYTES] {
+        self.0
+    }
+
+    // Bytes that do not represent a valid code are rejected, same as in `from_str`.
+    pub(crate) fn from_bytes(bytes: [u8; Self::BYTES]) -> Option<Self> {
+        let len = bytes.iter().position(|&b| b == 0).unwrap_or(Self::BYTES);
+        let code = std::str::from_utf8(&bytes[..len]).ok()?;
+        code.parse()
+            .ok()
+            .filter(|currency: &Self| currency.0 == bytes)
+    }
+
+    fn as_str(&self) -> &str {
+        let len = self.0.iter().position(|&b| b == 0).unwrap_or(Self::BYTES);
+        std::str::from_utf8(&self.0[..len]).expect("guaranteed to be ASCII")
+    }
+}
+
+/// Codes are case insensitive and consist of 1 to 8 ASCII letters or digits.
+impl std::str::FromStr for Currency {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.is_empty() || s.len() > Self::BYTES || !s.bytes().all(|b| b.is_ascii_alphanumeric()) {
+            return Err(format!("Invalid currency: {}", s));
+        }
+        let mut bytes = [0; Self::BYTES];
+        bytes[..s.len()].copy_from_slice(s.to_ascii_uppercase().as_bytes());
+        Ok(Self(bytes))
+    }
+}
+
+impl fmt::Display for Currency {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl Serialize for Currency {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
+impl<'de> Deserialize<'de> for Currency {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use test_case::test_case;
+
+    use super::Currency;
+
+    #[test_case("EUR" => Some("EUR".to_string()))]
+    #[test_case("gbp" => Some("GBP".to_string()))]
+    #[test_case("USDT" => Some("USDT".to_string()))]
+    #[test_case("" => None)]
+    #[test_case("TOOLONGCODE" => None)]
+    #[test_case("E-R" => None)]
+    fn parse(code: &str) -> Option<String> {
+        code.parse::<Currency>().ok().map(|c| c.to_string())
+    }
+
+    #[test]
+    fn bytes_roundtrip() {
+        let currency: Currency = "EUR".parse().unwrap();
+        assert_eq!(Currency::from_bytes(currency.to_bytes()), Some(currency));
+    }
+
+    #[test]
+    fn zeroed_bytes_are_rejected() {
+        assert_eq!(Currency::from_bytes([0; Currency::BYTES]), None);
+    }
+}
@@ -1,69 +1,388 @@
 //! In-memory database implementation for the `DepositValueCache` trait.
 //!
 //! Provides a simple in-memory cache for storing deposit values associated with transaction IDs.
-//! It is not production ready since it has no overflow protection implemented.
-
-use std::collections::HashMap;
+//! Without a pruning strategy the cache grows with every cached transaction, so for larger
+//! inputs either `PruningStrategy::Ttl` or `PruningStrategy::Size` should be used.
+//!
+//! The IDs of the evicted deposits are remembered for a while, so that they are neither
+//! accepted again nor confused with IDs that never existed. This memory is bounded by the
+//! strategy too: with `Size` at most `max_size` IDs are remembered, with `Ttl` they are
+//! forgotten once they are twice the `duration` old. After that, a dispute of such an ID is
+//! rejected as unknown and a new transaction with it is accepted.
 
-use crate::{
-    NonZero,
-    transaction::{Deposit, TransactionPayload},
+use std::{
+    collections::{HashMap, VecDeque},
+    num::NonZeroUsize,
+    time::{Duration, Instant},
 };
 
-use super::DepositValueCache;
+use super::{DepositValueCache, Disputable, Error};
+use crate::snapshot::{CachedSnapshot, PrunedSnapshot};
 
-pub(crate) enum Error {
-    AlreadyExists,
-}
-
-#[allow(dead_code)]
+/// Decides which deposits are evicted from the cache. Eviction happens lazily, when a new
+/// deposit is inserted, but expired deposits can not be disputed even before that.
 #[derive(Debug, Clone)]
-pub(super) enum PruningStrategy {
-    Ttl { duration: std::time::Duration },
-    Size { max_size: usize },
+pub enum PruningStrategy {
+    /// Evicts deposits that were cached longer than `duration` ago.
+    Ttl { duration: Duration },
+    /// Evicts the oldest deposits so that no more than `max_size` are cached.
+    Size { max_size: NonZeroUsize },
 }
 
 #[derive(Debug, Clone)]
 pub(crate) struct AmountCache {
-    txs: HashMap<u32, NonZero>,
+    txs: HashMap<u32, Disputable>,
+    // Instant every transaction was cached at. Only the `Ttl` strategy needs it, so the
+    // entries stay small otherwise.
+    cached_at: HashMap<u32, Instant>,
+    // Cached deposits, oldest first. Only maintained when there is a pruning strategy.
+    insertion_order: VecDeque<u32>,
+    // IDs of the evicted deposits, oldest first, and the instant they were cached at, or
+    // evicted at without the `Ttl` strategy.
+    pruned: HashMap<u32, Instant>,
+    pruned_order: VecDeque<u32>,
     pruning_strategy: Option<PruningStrategy>,
 }
 
+impl Default for AmountCache {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl AmountCache {
-    pub(crate) fn new() -> Self {
+    pub fn new() -> Self {
         Self {
             txs: HashMap::new(),
+            cached_at: HashMap::new(),
+            insertion_order: VecDeque::new(),
+            pruned: HashMap::new(),
+            pruned_order: VecDeque::new(),
             pruning_strategy: None,
         }
     }
-}
 
-impl DepositValueCache<NonZero> for AmountCache {
-    type Error = Error;
+    pub fn with_pruning_strategy(pruning_strategy: Option<PruningStrategy>) -> Self {
+        Self {
+            pruning_strategy,
+            ..Self::new()
+        }
+    }
+
+    /// The cached transactions, oldest first if there is a pruning strategy, together with
+    /// the IDs of the evicted ones, oldest first. Expired transactions count as evicted.
+    pub(crate) fn snapshot(&self) -> (Vec<CachedSnapshot>, Vec<PrunedSnapshot>) {
+        let now = Instant::now();
+        let age = |inserted_at: Instant| {
+            u64::try_from(now.duration_since(inserted_at).as_millis()).unwrap_or(u64::MAX)
+        };
+        let mut pruned: Vec<_> = self
+            .pruned_order
+            .iter()
+            .filter_map(|id| {
+                let inserted_at = self.pruned.get(id)?;
+                Some(PrunedSnapshot::new(*id, age(*inserted_at)))
+            })
+            .collect();
+        let cached = match self.pruning_strategy {
+            Some(_) => self
+                .insertion_order
+                .iter()
+                .filter_map(|id| {
+                    let value = self.txs.get(id)?;
+                    let cached_at = self.cached_at.get(id).copied();
+                    if self.expired(id, now) {
+                        pruned.push(PrunedSnapshot::new(*id, cached_at.map_or(0, age)));
+                        return None;
+                    }
+                    Some(CachedSnapshot::new(*id, *value, cached_at.map(age)))
+                })
+                .collect(),
+            None => {
+                let mut cached: Vec<_> = self
+                    .txs
+                    .iter()
+                    .map(|(id, value)| CachedSnapshot::new(*id, *value, None))
+                    .collect();
+                cached.sort_by_key(|cached| cached.tx);
+                cached
+            }
+        };
+        (cached, pruned)
+    }
 
-    fn get(&self, id: &u32) -> Option<&NonZero> {
-        self.txs.get(id)
+    /// Restores the cache from a snapshot. The ages of the cached transactions are only used
+    /// with the `Ttl` strategy.
+    pub(crate) fn restore(
+        pruning_strategy: Option<PruningStrategy>,
+        cached: &[CachedSnapshot],
+        pruned: &[PrunedSnapshot],
+    ) -> Self {
+        let now = Instant::now();
+        let inserted_at = |age_ms| {
+            now.checked_sub(Duration::from_millis(age_ms))
+                .unwrap_or(now)
+        };
+        let mut cache = Self::with_pruning_strategy(pruning_strategy);
+        for snapshot in cached {
+            cache.txs.insert(snapshot.tx, snapshot.value());
+            if cache.has_ttl() {
+                let age_ms = snapshot.age_ms.unwrap_or_default();
+                cache.cached_at.insert(snapshot.tx, inserted_at(age_ms));
+            }
+            if cache.pruning_strategy.is_some() {
+                cache.insertion_order.push_back(snapshot.tx);
+            }
+        }
+        if cache.pruning_strategy.is_some() {
+            for snapshot in pruned {
+                cache.remember_pruned(snapshot.tx, inserted_at(snapshot.age_ms));
+            }
+            cache.forget_pruned(now);
+        }
+        cache
     }
 
-    fn insert(&mut self, id: u32, tx: TransactionPayload<Deposit>) -> Result<(), Self::Error> {
-        if let Some(strategy) = &self.pruning_strategy {
-            match strategy {
-                PruningStrategy::Size { max_size: _ } | PruningStrategy::Ttl { duration: _ } => {
-                    // TODO: Implement proper pruning strategy
+    fn has_ttl(&self) -> bool {
+        matches!(self.pruning_strategy, Some(PruningStrategy::Ttl { .. }))
+    }
+
+    // Whether the cached transaction can no longer be disputed.
+    fn expired(&self, id: &u32, now: Instant) -> bool {
+        match (&self.pruning_strategy, self.cached_at.get(id)) {
+            (Some(PruningStrategy::Ttl { duration }), Some(cached_at)) => {
+                now.duration_since(*cached_at) > *duration
+            }
+            _ => false,
+        }
+    }
+
+    // Makes room for a single new entry, according to the pruning strategy.
+    fn prune(&mut self, now: Instant) {
+        let Some(strategy) = &self.pruning_strategy else {
+            return;
+        };
+        let strategy = strategy.clone();
+        while let Some(id) = self.insertion_order.front().copied() {
+            let evict = match strategy {
+                PruningStrategy::Ttl { .. } => self.expired(&id, now),
+                PruningStrategy::Size { max_size } => self.txs.len() >= max_size.get(),
+            };
+            if !evict {
+                break;
+            }
+            self.insertion_order.pop_front();
+            let cached_at = self.cached_at.remove(&id).unwrap_or(now);
+            if self.txs.remove(&id).is_some() {
+                self.remember_pruned(id, cached_at);
+            }
+        }
+        self.forget_pruned(now);
+    }
+
+    fn remember_pruned(&mut self, id: u32, inserted_at: Instant) {
+        if self.pruned.insert(id, inserted_at).is_none() {
+            self.pruned_order.push_back(id);
+        }
+    }
+
+    // Forgets the oldest evicted IDs beyond the bound of the pruning strategy.
+    fn forget_pruned(&mut self, now: Instant) {
+        while let Some(id) = self.pruned_order.front().copied() {
+            let forget = match self.pruning_strategy {
+                Some(PruningStrategy::Ttl { duration }) => self
+                    .pruned
+                    .get(&id)
+                    .is_none_or(|inserted_at| now.duration_since(*inserted_at) > duration * 2),
+                Some(PruningStrategy::Size { max_size }) => {
+                    self.pruned_order.len() > max_size.get()
                 }
+                None => false,
+            };
+            if !forget {
+                break;
+            }
+            self.pruned_order.pop_front();
+            self.pruned.remove(&id);
+        }
+    }
+}
+
+impl DepositValueCache<Disputable> for AmountCache {
+    fn get(&self, id: &u32) -> Result<Option<Disputable>, Error> {
+        let now = Instant::now();
+        Ok(self.txs.get(id).filter(|_| !self.expired(id, now)).copied())
+    }
+
+    fn insert(&mut self, id: u32, value: Disputable) -> Result<(), Error> {
+        if self.txs.contains_key(&id) {
+            return Err(Error::AlreadyExists);
+        }
+
+        let now = Instant::now();
+        self.prune(now);
+        if self.pruning_strategy.is_some() {
+            self.insertion_order.push_back(id);
+        }
+        if self.has_ttl() {
+            self.cached_at.insert(id, now);
+        }
+        self.txs.insert(id, value);
+        Ok(())
+    }
+
+    fn remove(&mut self, id: u32) -> Result<Option<Disputable>, Error> {
+        let value = self.txs.remove(&id);
+        self.cached_at.remove(&id);
+        // Otherwise the ID, once inserted again, would be evicted in the place of the
+        // removed entry. Removed transactions are usually the latest, e.g. a refunded
+        // transfer, so they are searched for from the back.
+        if value.is_some() {
+            if let Some(index) = self
+                .insertion_order
+                .iter()
+                .rposition(|queued| *queued == id)
+            {
+                self.insertion_order.remove(index);
             }
         }
+        Ok(value)
+    }
+
+    fn is_pruned(&self, id: &u32) -> bool {
+        self.pruned.contains_key(id) || self.expired(id, Instant::now())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        num::NonZeroUsize,
+        time::{Duration, Instant},
+    };
+
+    use crate::{
+        amount::Amount,
+        db::{
+            DepositValueCache, Disputable, Error,
+            in_mem::{AmountCache, PruningStrategy},
+        },
+    };
 
-        let amount = tx.amount();
-        match self.txs.insert(id, *amount) {
-            Some(_) => Err(Error::AlreadyExists),
-            None => Ok(()),
+    fn deposit() -> Disputable {
+        Disputable::Deposit {
+            amount: Amount::from(1).try_into().unwrap(),
+            currency: None,
         }
     }
 
-    #[allow(dead_code)]
-    // To could be helpful when pruning is implemented.
-    fn remove(&mut self, id: u32) -> Option<NonZero> {
-        self.txs.remove(&id)
+    #[test]
+    fn rejects_duplicates() {
+        let mut cache = AmountCache::new();
+        assert!(cache.insert(1, deposit()).is_ok());
+        assert!(matches!(
+            cache.insert(1, deposit()),
+            Err(Error::AlreadyExists)
+        ));
+    }
+
+    fn size(max_size: usize) -> Option<PruningStrategy> {
+        let max_size = NonZeroUsize::new(max_size).unwrap();
+        Some(PruningStrategy::Size { max_size })
+    }
+
+    #[test]
+    fn size_evicts_oldest() {
+        let mut cache = AmountCache::with_pruning_strategy(size(2));
+        for tx in 1..=3 {
+            assert!(cache.insert(tx, deposit()).is_ok());
+        }
+
+        assert!(matches!(cache.get(&1), Ok(None)));
+        assert!(cache.is_pruned(&1));
+        assert!(matches!(cache.get(&2), Ok(Some(_))));
+        assert!(matches!(cache.get(&3), Ok(Some(_))));
+        // Only the TTL needs the instants.
+        assert!(cache.cached_at.is_empty());
+    }
+
+    #[test]
+    fn removed_id_is_evicted_in_its_new_place() {
+        let mut cache = AmountCache::with_pruning_strategy(size(3));
+        assert!(cache.insert(1, deposit()).is_ok());
+        assert!(cache.insert(2, deposit()).is_ok());
+        assert!(matches!(cache.remove(1), Ok(Some(_))));
+        assert!(cache.insert(3, deposit()).is_ok());
+        assert!(cache.insert(1, deposit()).is_ok());
+
+        // 2 is the oldest now.
+        assert!(cache.insert(4, deposit()).is_ok());
+        assert!(cache.is_pruned(&2));
+        assert!(matches!(cache.get(&1), Ok(Some(_))));
+    }
+
+    #[test]
+    fn ttl_evicts_expired() {
+        let duration = Duration::from_secs(60);
+        let mut cache = AmountCache::with_pruning_strategy(Some(PruningStrategy::Ttl { duration }));
+        assert!(cache.insert(1, deposit()).is_ok());
+        assert!(cache.insert(2, deposit()).is_ok());
+
+        cache.prune(Instant::now());
+        assert!(matches!(cache.get(&1), Ok(Some(_))));
+
+        cache.prune(Instant::now() + duration + Duration::from_secs(1));
+        assert!(matches!(cache.get(&1), Ok(None)));
+        assert!(matches!(cache.get(&2), Ok(None)));
+        assert!(cache.is_pruned(&1));
+        assert!(cache.is_pruned(&2));
+    }
+
+    #[test]
+    fn snapshot_roundtrip() {
+        let strategy = size(2);
+        let mut cache = AmountCache::with_pruning_strategy(strategy.clone());
+        for tx in [3, 1, 2] {
+            assert!(cache.insert(tx, deposit()).is_ok());
+        }
+
+        let (cached, pruned) = cache.snapshot();
+        let mut restored = AmountCache::restore(strategy, &cached, &pruned);
+        assert!(restored.is_pruned(&3));
+        // The oldest of the restored deposits is evicted first.
+        assert!(restored.insert(4, deposit()).is_ok());
+        assert!(restored.is_pruned(&1));
+        assert!(matches!(restored.get(&2), Ok(Some(_))));
+    }
+
+    #[test]
+    fn never_inserted_is_not_pruned() {
+        let mut cache = AmountCache::with_pruning_strategy(size(1));
+        assert!(cache.insert(1, deposit()).is_ok());
+        assert!(!cache.is_pruned(&2));
+    }
+
+    #[test]
+    fn expired_can_not_be_found_before_eviction() {
+        let duration = Duration::from_millis(1);
+        let mut cache = AmountCache::with_pruning_strategy(Some(PruningStrategy::Ttl { duration }));
+        assert!(cache.insert(1, deposit()).is_ok());
+        std::thread::sleep(duration * 2);
+
+        assert!(matches!(cache.get(&1), Ok(None)));
+        assert!(cache.is_pruned(&1));
+    }
+
+    #[test]
+    fn pruned_ids_are_bounded() {
+        let mut cache = AmountCache::with_pruning_strategy(size(2));
+        for tx in 1..=5 {
+            assert!(cache.insert(tx, deposit()).is_ok());
+        }
+
+        // 1, 2 and 3 were evicted, only the last two of them are remembered.
+        assert!(!cache.is_pruned(&1));
+        assert!(cache.is_pruned(&2));
+        assert!(cache.is_pruned(&3));
     }
 }
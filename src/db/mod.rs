@@ -1,8 +1,272 @@
 //! The database module for the transaction processor.
 //!
-//! Database is needed to store the deposit values which are needed when dispute is created.
+//! Database is needed to store the deposit (and optionally withdrawal) values which are
+//! needed when dispute is created. Transfers are stored too, only to reserve their IDs.
+//! The deposits can be kept either in memory or in a file on disk, or in a storage of the
+//! embedding service implementing `DepositValueCache`. Which one is used is decided per
+//! run by the `Backend`.
 
-pub(super) mod in_mem;
+use std::{fmt, sync::Arc};
+
+use thiserror::Error;
+
+use crate::{
+    NonZero,
+    currency::Currency,
+    transaction::{Credit, Deposit, TransactionPayload, Transfer, Withdrawal},
+};
+
+pub mod in_mem;
+pub mod on_disk;
 mod traits;
 
-pub(super) use traits::DepositValueCache;
+pub use traits::DepositValueCache;
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("value already exists")]
+    AlreadyExists,
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+}
+
+/// A cached transaction that can be disputed later.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Disputable {
+    Deposit {
+        amount: NonZero,
+        currency: Option<Currency>,
+    },
+    Withdrawal {
+        amount: NonZero,
+        currency: Option<Currency>,
+    },
+    /// Either side of a transfer. It can not be disputed, it is only cached so that its ID
+    /// is not reused.
+    Transfer {
+        amount: NonZero,
+        currency: Option<Currency>,
+    },
+}
+
+impl Disputable {
+    pub fn amount(&self) -> NonZero {
+        match self {
+            Self::Deposit { amount, .. }
+            | Self::Withdrawal { amount, .. }
+            | Self::Transfer { amount, .. } => *amount,
+        }
+    }
+
+    pub fn currency(&self) -> Option<Currency> {
+        match self {
+            Self::Deposit { currency, .. }
+            | Self::Withdrawal { currency, .. }
+            | Self::Transfer { currency, .. } => *currency,
+        }
+    }
+}
+
+impl From<&TransactionPayload<Deposit>> for Disputable {
+    fn from(tx: &TransactionPayload<Deposit>) -> Self {
+        Self::Deposit {
+            amount: *tx.amount(),
+            currency: tx.currency(),
+        }
+    }
+}
+
+impl From<&TransactionPayload<Transfer>> for Disputable {
+    fn from(tx: &TransactionPayload<Transfer>) -> Self {
+        Self::Transfer {
+            amount: *tx.amount(),
+            currency: tx.currency(),
+        }
+    }
+}
+
+impl From<&TransactionPayload<Credit>> for Disputable {
+    fn from(tx: &TransactionPayload<Credit>) -> Self {
+        Self::Transfer {
+            amount: *tx.amount(),
+            currency: tx.currency(),
+        }
+    }
+}
+
+impl From<&TransactionPayload<Withdrawal>> for Disputable {
+    fn from(tx: &TransactionPayload<Withdrawal>) -> Self {
+        Self::Withdrawal {
+            amount: *tx.amount(),
+            currency: tx.currency(),
+        }
+    }
+}
+
+/// Creates the deposit cache of a client, given its ID.
+pub type CacheFactory =
+    Arc<dyn Fn(u16) -> Box<dyn DepositValueCache<Disputable> + Send> + Send + Sync>;
+
+/// Storage used for the deposit caches of all clients.
+///
+/// ```
+/// use std::{collections::HashMap, sync::Arc};
+///
+/// use futures_util::{StreamExt, stream};
+/// use tx_processor::{
+///     InputAmount, StreamProcessor,
+///     csv::{InputRecord, Kind},
+///     db::{Backend, DepositValueCache, Disputable, Error},
+/// };
+///
+/// // E.g. a cache shared with other services.
+/// #[derive(Default)]
+/// struct Shared(HashMap<u32, Disputable>);
+///
+/// impl DepositValueCache<Disputable> for Shared {
+///     fn get(&self, id: &u32) -> Result<Option<Disputable>, Error> {
+///         Ok(self.0.get(id).copied())
+///     }
+///
+///     fn insert(&mut self, id: u32, value: Disputable) -> Result<(), Error> {
+///         match self.0.insert(id, value) {
+///             Some(_) => Err(Error::AlreadyExists),
+///             None => Ok(()),
+///         }
+///     }
+///
+///     fn remove(&mut self, id: u32) -> Result<Option<Disputable>, Error> {
+///         Ok(self.0.remove(&id))
+///     }
+///
+///     fn is_pruned(&self, _id: &u32) -> bool {
+///         false
+///     }
+/// }
+///
+/// # #[tokio::main]
+/// # async fn main() {
+/// let backend = Backend::Custom {
+///     factory: Arc::new(|_client| Box::new(Shared::default())),
+/// };
+/// let records = vec![
+///     InputRecord::new(Kind::Deposit, 1, 1).with_amount("1.5".parse::<InputAmount>().unwrap()),
+///     InputRecord::new(Kind::Dispute, 1, 1),
+/// ];
+/// let mut processor = StreamProcessor::new().with_backend(backend);
+/// let states: Vec<_> = processor
+///     .process(stream::iter(records.into_iter().map(Ok)))
+///     .await
+///     .collect()
+///     .await;
+/// let (_, balances) = states[0].as_ref().unwrap().balances().next().unwrap();
+/// assert_eq!(balances.held().fixed(1).to_string(), "1.5");
+/// # }
+/// ```
+#[derive(Clone)]
+pub enum Backend {
+    InMemory {
+        pruning_strategy: Option<in_mem::PruningStrategy>,
+    },
+    OnDisk {
+        store: Arc<on_disk::Store>,
+    },
+    /// A cache of the embedding service, created for every client. Like the on-disk
+    /// cache, it is not part of the snapshots and checkpoints.
+    Custom {
+        factory: CacheFactory,
+    },
+}
+
+impl fmt::Debug for Backend {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InMemory { pruning_strategy } => f
+                .debug_struct("InMemory")
+                .field("pruning_strategy", pruning_strategy)
+                .finish(),
+            Self::OnDisk { store } => f.debug_struct("OnDisk").field("store", store).finish(),
+            Self::Custom { .. } => f.debug_struct("Custom").finish_non_exhaustive(),
+        }
+    }
+}
+
+impl Backend {
+    /// Creates a deposit cache for a single client.
+    pub(crate) fn cache(&self, client: u16) -> Cache {
+        match self {
+            Self::InMemory { pruning_strategy } => Cache::InMemory(
+                in_mem::AmountCache::with_pruning_strategy(pruning_strategy.clone()),
+            ),
+            Self::OnDisk { store } => {
+                Cache::OnDisk(on_disk::DiskCache::new(Arc::clone(store), client))
+            }
+            Self::Custom { factory } => Cache::Custom(factory(client)),
+        }
+    }
+
+    /// Whether the deposits are kept in memory, which only the snapshots and checkpoints
+    /// can capture.
+    pub(crate) fn is_in_memory(&self) -> bool {
+        matches!(self, Self::InMemory { .. })
+    }
+}
+
+impl Default for Backend {
+    fn default() -> Self {
+        Self::InMemory {
+            pruning_strategy: None,
+        }
+    }
+}
+
+/// Deposit cache of a single client, backed by the storage selected with `Backend`.
+pub(crate) enum Cache {
+    InMemory(in_mem::AmountCache),
+    OnDisk(on_disk::DiskCache),
+    Custom(Box<dyn DepositValueCache<Disputable> + Send>),
+}
+
+impl fmt::Debug for Cache {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InMemory(cache) => f.debug_tuple("InMemory").field(cache).finish(),
+            Self::OnDisk(cache) => f.debug_tuple("OnDisk").field(cache).finish(),
+            Self::Custom(_) => f.debug_tuple("Custom").finish_non_exhaustive(),
+        }
+    }
+}
+
+impl DepositValueCache<Disputable> for Cache {
+    fn get(&self, id: &u32) -> Result<Option<Disputable>, Error> {
+        match self {
+            Self::InMemory(cache) => cache.get(id),
+            Self::OnDisk(cache) => cache.get(id),
+            Self::Custom(cache) => cache.get(id),
+        }
+    }
+
+    fn insert(&mut self, id: u32, value: Disputable) -> Result<(), Error> {
+        match self {
+            Self::InMemory(cache) => cache.insert(id, value),
+            Self::OnDisk(cache) => cache.insert(id, value),
+            Self::Custom(cache) => cache.insert(id, value),
+        }
+    }
+
+    fn remove(&mut self, id: u32) -> Result<Option<Disputable>, Error> {
+        match self {
+            Self::InMemory(cache) => cache.remove(id),
+            Self::OnDisk(cache) => cache.remove(id),
+            Self::Custom(cache) => cache.remove(id),
+        }
+    }
+
+    fn is_pruned(&self, id: &u32) -> bool {
+        match self {
+            Self::InMemory(cache) => cache.is_pruned(id),
+            Self::OnDisk(cache) => cache.is_pruned(id),
+            Self::Custom(cache) => cache.is_pruned(id),
+        }
+    }
+}
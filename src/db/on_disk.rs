@@ -0,0 +1,373 @@
+//! On-disk database implementation for the `DepositValueCache` trait.
+//!
+//! Cached transactions of all clients are kept in a single file. Transaction IDs are `u32`, so instead
+//! of maintaining an index, every transaction has a fixed slot at `id * RECORD_SIZE`.
+//! The file is sparse, only the slots that were written take up disk space, and the
+//! memory footprint does not depend on the number of cached transactions at all.
+//!
+//! Like with the in-memory cache, transaction IDs only need to be unique per client. The
+//! slots are shared though, so a transaction whose slot is taken by another client is kept
+//! in memory instead. That should be rare, since the IDs are meant to be globally unique.
+//!
+//! Reads and writes are blocking. They mostly hit the operating system page cache, so
+//! they are cheap enough to be done directly from within the client processor tasks.
+//! Since the shards share the file, a slot is locked while it is read and written back.
+
+use std::{
+    collections::HashMap,
+    fs::{File, OpenOptions},
+    io,
+    path::Path,
+    sync::{Arc, Mutex, MutexGuard},
+};
+
+use crate::{NonZero, currency::Currency};
+
+use super::{DepositValueCache, Disputable, Error};
+
+// Record layout: [state, has currency, client (2 bytes, LE), amount, currency]
+const RECORD_SIZE: usize = 4 + NonZero::BYTES + Currency::BYTES;
+const AMOUNT: std::ops::Range<usize> = 4..4 + NonZero::BYTES;
+const CURRENCY: std::ops::Range<usize> = AMOUNT.end..RECORD_SIZE;
+const EMPTY: u8 = 0;
+const DEPOSIT: u8 = 1;
+const WITHDRAWAL: u8 = 2;
+const TRANSFER: u8 = 3;
+
+// Slots are locked in stripes, so that unrelated IDs rarely wait for each other.
+const LOCK_STRIPES: usize = 64;
+
+struct Record {
+    client: u16,
+    value: Disputable,
+}
+
+impl Record {
+    fn encode(&self) -> [u8; RECORD_SIZE] {
+        let mut bytes = [0; RECORD_SIZE];
+        bytes[0] = match self.value {
+            Disputable::Deposit { .. } => DEPOSIT,
+            Disputable::Withdrawal { .. } => WITHDRAWAL,
+            Disputable::Transfer { .. } => TRANSFER,
+        };
+        bytes[2..4].copy_from_slice(&self.client.to_le_bytes());
+        bytes[AMOUNT].copy_from_slice(&self.value.amount().to_bytes());
+        if let Some(currency) = self.value.currency() {
+            bytes[1] = 1;
+            bytes[CURRENCY].copy_from_slice(&currency.to_bytes());
+        }
+        bytes
+    }
+
+    fn decode(bytes: [u8; RECORD_SIZE]) -> io::Result<Option<Self>> {
+        let state = bytes[0];
+        if state == EMPTY {
+            return Ok(None);
+        }
+        let client = u16::from_le_bytes([bytes[2], bytes[3]]);
+        let mut amount = [0; NonZero::BYTES];
+        amount.copy_from_slice(&bytes[AMOUNT]);
+        let amount = NonZero::from_bytes(amount).ok_or_else(corrupted)?;
+        let currency = match bytes[1] {
+            0 => None,
+            _ => {
+                let mut currency = [0; Currency::BYTES];
+                currency.copy_from_slice(&bytes[CURRENCY]);
+                Some(Currency::from_bytes(currency).ok_or_else(corrupted)?)
+            }
+        };
+        let value = match state {
+            DEPOSIT => Disputable::Deposit { amount, currency },
+            WITHDRAWAL => Disputable::Withdrawal { amount, currency },
+            TRANSFER => Disputable::Transfer { amount, currency },
+            _ => return Err(corrupted()),
+        };
+        Ok(Some(Self { client, value }))
+    }
+}
+
+fn corrupted() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "corrupted transaction record")
+}
+
+fn offset(id: u32) -> u64 {
+    u64::from(id) * RECORD_SIZE as u64
+}
+
+/// The file with cached transactions, shared by the caches of all clients.
+#[derive(Debug)]
+pub struct Store {
+    file: File,
+    locks: [Mutex<()>; LOCK_STRIPES],
+}
+
+impl Store {
+    /// Creates a new store at `path`, discarding any previous content of the file.
+    pub fn create<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)?;
+        Ok(Self {
+            file,
+            locks: std::array::from_fn(|_| Mutex::new(())),
+        })
+    }
+
+    // Held while the slot of `id` is read and then written, so that the write is not based
+    // on a stale read.
+    fn lock(&self, id: u32) -> MutexGuard<'_, ()> {
+        // Nothing is guarded but the file, which a panic does not leave half-written.
+        self.locks[id as usize % LOCK_STRIPES]
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    fn read(&self, id: u32) -> io::Result<Option<Record>> {
+        let mut bytes = [0; RECORD_SIZE];
+        match read_exact_at(&self.file, &mut bytes, offset(id)) {
+            // Slots past the end of the file were never written.
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => Ok(None),
+            Err(err) => Err(err),
+            Ok(()) => Record::decode(bytes),
+        }
+    }
+
+    fn write(&self, id: u32, bytes: &[u8; RECORD_SIZE]) -> io::Result<()> {
+        write_all_at(&self.file, bytes, offset(id))
+    }
+}
+
+#[cfg(unix)]
+fn read_exact_at(file: &File, buf: &mut [u8], offset: u64) -> io::Result<()> {
+    std::os::unix::fs::FileExt::read_exact_at(file, buf, offset)
+}
+
+#[cfg(unix)]
+fn write_all_at(file: &File, buf: &[u8], offset: u64) -> io::Result<()> {
+    std::os::unix::fs::FileExt::write_all_at(file, buf, offset)
+}
+
+#[cfg(windows)]
+fn read_exact_at(file: &File, mut buf: &mut [u8], mut offset: u64) -> io::Result<()> {
+    use std::os::windows::fs::FileExt;
+    while !buf.is_empty() {
+        match file.seek_read(buf, offset)? {
+            0 => return Err(io::ErrorKind::UnexpectedEof.into()),
+            n => {
+                buf = &mut buf[n..];
+                offset += n as u64;
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(windows)]
+fn write_all_at(file: &File, mut buf: &[u8], mut offset: u64) -> io::Result<()> {
+    use std::os::windows::fs::FileExt;
+    while !buf.is_empty() {
+        match file.seek_write(buf, offset)? {
+            0 => return Err(io::ErrorKind::WriteZero.into()),
+            n => {
+                buf = &buf[n..];
+                offset += n as u64;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// A view of the `Store` limited to the transactions of a single client.
+#[derive(Debug)]
+pub(crate) struct DiskCache {
+    store: Arc<Store>,
+    client: u16,
+    // Transactions whose slot is taken by another client.
+    overflow: HashMap<u32, Disputable>,
+}
+
+impl DiskCache {
+    pub fn new(store: Arc<Store>, client: u16) -> Self {
+        Self {
+            store,
+            client,
+            overflow: HashMap::new(),
+        }
+    }
+}
+
+impl DepositValueCache<Disputable> for DiskCache {
+    fn get(&self, id: &u32) -> Result<Option<Disputable>, Error> {
+        let _slot = self.store.lock(*id);
+        match self.store.read(*id)? {
+            Some(record) if record.client == self.client => Ok(Some(record.value)),
+            _ => Ok(self.overflow.get(id).copied()),
+        }
+    }
+
+    fn insert(&mut self, id: u32, value: Disputable) -> Result<(), Error> {
+        // Kept here even if the other client has freed the slot meanwhile.
+        if self.overflow.contains_key(&id) {
+            return Err(Error::AlreadyExists);
+        }
+        let _slot = self.store.lock(id);
+        match self.store.read(id)? {
+            Some(record) if record.client == self.client => Err(Error::AlreadyExists),
+            Some(_) => {
+                self.overflow.insert(id, value);
+                Ok(())
+            }
+            None => {
+                let record = Record {
+                    client: self.client,
+                    value,
+                };
+                self.store.write(id, &record.encode())?;
+                Ok(())
+            }
+        }
+    }
+
+    fn remove(&mut self, id: u32) -> Result<Option<Disputable>, Error> {
+        let _slot = self.store.lock(id);
+        match self.store.read(id)? {
+            Some(record) if record.client == self.client => {
+                self.store.write(id, &[EMPTY; RECORD_SIZE])?;
+                Ok(Some(record.value))
+            }
+            _ => Ok(self.overflow.remove(&id)),
+        }
+    }
+
+    fn is_pruned(&self, _id: &u32) -> bool {
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use crate::{
+        amount::Amount,
+        db::{
+            DepositValueCache, Disputable, Error,
+            on_disk::{DiskCache, Store},
+        },
+    };
+
+    fn store() -> Arc<Store> {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        Arc::new(Store::create(file.path()).unwrap())
+    }
+
+    fn deposit(amount: u32) -> Disputable {
+        Disputable::Deposit {
+            amount: Amount::from(amount).try_into().unwrap(),
+            currency: None,
+        }
+    }
+
+    fn withdrawal(amount: u32) -> Disputable {
+        Disputable::Withdrawal {
+            amount: Amount::from(amount).try_into().unwrap(),
+            currency: Some("EUR".parse().unwrap()),
+        }
+    }
+
+    #[test]
+    fn insert_get_remove() {
+        let mut cache = DiskCache::new(store(), 1);
+
+        assert!(matches!(cache.get(&7), Ok(None)));
+        assert!(cache.insert(7, deposit(10)).is_ok());
+        assert!(cache.insert(8, withdrawal(5)).is_ok());
+        assert!(cache.insert(u32::MAX, deposit(20)).is_ok());
+
+        assert_eq!(cache.get(&7).unwrap(), Some(deposit(10)));
+        assert_eq!(cache.get(&8).unwrap(), Some(withdrawal(5)));
+        assert_eq!(cache.get(&u32::MAX).unwrap(), Some(deposit(20)));
+        assert!(matches!(
+            cache.insert(7, deposit(10)),
+            Err(Error::AlreadyExists)
+        ));
+
+        assert!(matches!(cache.remove(7), Ok(Some(_))));
+        assert!(matches!(cache.get(&7), Ok(None)));
+    }
+
+    #[test]
+    fn clients_do_not_see_each_others_deposits() {
+        let store = store();
+        let mut first = DiskCache::new(Arc::clone(&store), 1);
+        let mut second = DiskCache::new(store, 2);
+
+        assert!(first.insert(1, deposit(10)).is_ok());
+        assert!(matches!(second.get(&1), Ok(None)));
+        assert!(matches!(second.remove(1), Ok(None)));
+        assert!(matches!(first.get(&1), Ok(Some(_))));
+    }
+
+    #[test]
+    fn ids_are_unique_per_client() {
+        let store = store();
+        let mut first = DiskCache::new(Arc::clone(&store), 1);
+        let mut second = DiskCache::new(store, 2);
+
+        assert!(first.insert(1, deposit(10)).is_ok());
+        assert!(second.insert(1, deposit(7)).is_ok());
+        assert!(matches!(
+            second.insert(1, deposit(7)),
+            Err(Error::AlreadyExists)
+        ));
+        assert_eq!(first.get(&1).unwrap(), Some(deposit(10)));
+        assert_eq!(second.get(&1).unwrap(), Some(deposit(7)));
+
+        assert_eq!(second.remove(1).unwrap(), Some(deposit(7)));
+        assert_eq!(first.get(&1).unwrap(), Some(deposit(10)));
+    }
+
+    #[test]
+    fn overflow_is_checked_when_the_slot_is_freed() {
+        let store = store();
+        let mut first = DiskCache::new(Arc::clone(&store), 1);
+        let mut second = DiskCache::new(store, 2);
+
+        assert!(first.insert(1, deposit(10)).is_ok());
+        assert!(second.insert(1, deposit(7)).is_ok());
+        assert_eq!(first.remove(1).unwrap(), Some(deposit(10)));
+        assert!(matches!(
+            second.insert(1, deposit(7)),
+            Err(Error::AlreadyExists)
+        ));
+        assert_eq!(second.get(&1).unwrap(), Some(deposit(7)));
+    }
+
+    #[test]
+    fn concurrent_clients_keep_their_deposits() {
+        let store = store();
+        let start = Arc::new(std::sync::Barrier::new(4));
+        let handles: Vec<_> = (1..=4)
+            .map(|client| {
+                let mut cache = DiskCache::new(Arc::clone(&store), client);
+                let start = Arc::clone(&start);
+                std::thread::spawn(move || {
+                    start.wait();
+                    for id in 0..20_000 {
+                        cache.insert(id, deposit(u32::from(client))).unwrap();
+                    }
+                    cache
+                })
+            })
+            .collect();
+        for (client, handle) in (1..=4).zip(handles) {
+            let cache = handle.join().unwrap();
+            for id in 0..20_000 {
+                assert_eq!(cache.get(&id).unwrap(), Some(deposit(client)));
+            }
+        }
+    }
+}
@@ -1,17 +1,23 @@
 //! Traits for the database module.
 
-use crate::transaction::{Deposit, TransactionPayload};
+use super::Error;
 
-/// A trait for caching deposit values in the database. It won't work
-/// with transactions other than deposit.
+/// A trait for caching the values of transactions which can be disputed
+/// (deposits and, depending on the configuration, withdrawals) in the database.
+/// Transfers are cached too, so that their IDs are not reused.
+///
+/// Every client has a cache of its own, so the IDs only have to be unique per cache.
 pub trait DepositValueCache<ValueType> {
-    type Error;
+    fn get(&self, id: &u32) -> Result<Option<ValueType>, Error>;
 
-    fn get(&self, id: &u32) -> Option<&ValueType>;
+    /// Fails with `Error::AlreadyExists` if there is already a value for the given `id`.
+    fn insert(&mut self, id: u32, value: ValueType) -> Result<(), Error>;
 
-    fn insert(&mut self, id: u32, tx: TransactionPayload<Deposit>) -> Result<(), Self::Error>;
+    /// Removes the value from the cache, e.g. the transfer of a refund, whose ID can then
+    /// be used again.
+    fn remove(&mut self, id: u32) -> Result<Option<ValueType>, Error>;
 
-    #[allow(dead_code)]
-    // To could be helpful when pruning is implemented.
-    fn remove(&mut self, id: u32) -> Option<ValueType>;
+    /// Tells whether the value was evicted by a pruning strategy, as opposed to never
+    /// being inserted at all.
+    fn is_pruned(&self, id: &u32) -> bool;
 }
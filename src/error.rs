@@ -1,13 +1,27 @@
 use thiserror::Error;
 
-use crate::balances;
+use crate::{balances, db};
 
 #[derive(Error, Debug)]
 pub(super) enum Error {
-    #[error("Invalid transaction: {id}")]
-    InvalidTransaction { id: u32 },
     #[error("Duplicated transaction: {id}")]
     DuplicatedTransaction { id: u32 },
+    #[error("Unknown transaction: {id}")]
+    UnknownTransaction { id: u32 },
+    #[error("Transaction {id} is already disputed")]
+    AlreadyDisputed { id: u32 },
+    #[error("Transaction {id} is not disputed")]
+    NotDisputed { id: u32 },
+    #[error("Transaction {id} was pruned from the deposit cache")]
+    PrunedTransaction { id: u32 },
+    #[error("Account of client {client} is locked")]
+    AccountLocked { client: u16 },
+    #[error("Account of client {client} is not locked")]
+    AccountNotLocked { client: u16 },
+    #[error("Shard of client {client} is not available")]
+    ShardUnavailable { client: u16 },
     #[error(transparent)]
     Balances(#[from] balances::Error),
+    #[error(transparent)]
+    Database(#[from] db::Error),
 }
@@ -0,0 +1,235 @@
+//! Minimal HTTP interface to the live client states in the serve mode.
+//!
+//! Only `GET /clients/{id}` is supported and every response closes the connection. It is
+//! meant for occasional lookups, e.g. by support staff, so a full HTTP stack is not needed.
+
+use std::io;
+
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpStream},
+    sync::{mpsc, oneshot},
+};
+use tx_processor::{ClientState, json};
+
+// Queries waiting for the serve loop, beyond that the connections wait.
+const QUERY_CHANNEL_SIZE: usize = 100;
+
+// The request line and the headers must fit, the body is never read.
+const MAX_REQUEST_SIZE: u64 = 8 * 1024;
+
+const OK: &str = "200 OK";
+const BAD_REQUEST: &str = "400 Bad Request";
+const NOT_FOUND: &str = "404 Not Found";
+const METHOD_NOT_ALLOWED: &str = "405 Method Not Allowed";
+const INTERNAL_SERVER_ERROR: &str = "500 Internal Server Error";
+const SERVICE_UNAVAILABLE: &str = "503 Service Unavailable";
+
+/// A request for the current state of a client, answered by the serve loop.
+pub(super) struct Query {
+    pub(super) client: u16,
+    pub(super) reply: oneshot::Sender<Result<Option<ClientState>, tx_processor::Error>>,
+}
+
+pub(super) fn channel() -> (mpsc::Sender<Query>, mpsc::Receiver<Query>) {
+    mpsc::channel(QUERY_CHANNEL_SIZE)
+}
+
+/// Answers the requests until the task is dropped. The balances have `decimal_places`.
+pub(super) async fn listen(
+    listener: TcpListener,
+    decimal_places: usize,
+    queries: mpsc::Sender<Query>,
+) {
+    loop {
+        let (socket, peer) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(err) => {
+                tracing::error!(%err, "could not accept HTTP connection");
+                continue;
+            }
+        };
+        let queries = queries.clone();
+        tokio::spawn(async move {
+            if let Err(err) = respond(socket, decimal_places, &queries).await {
+                tracing::warn!(%err, %peer, "could not answer HTTP request");
+            }
+        });
+    }
+}
+
+async fn respond(
+    socket: TcpStream,
+    decimal_places: usize,
+    queries: &mpsc::Sender<Query>,
+) -> io::Result<()> {
+    let (reader, mut writer) = socket.into_split();
+    let mut reader = BufReader::new(reader.take(MAX_REQUEST_SIZE));
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line).await?;
+    // The headers are skipped, but read, so that the client is not reset before it gets
+    // the response.
+    loop {
+        let mut header = String::new();
+        if reader.read_line(&mut header).await? == 0 || header.trim().is_empty() {
+            break;
+        }
+    }
+
+    let (status, body) = response(&request_line, decimal_places, queries).await;
+    let head = format!(
+        "HTTP/1.1 {status}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        body.len()
+    );
+    writer.write_all(head.as_bytes()).await?;
+    writer.write_all(body.as_bytes()).await?;
+    writer.shutdown().await
+}
+
+// The status and the JSON body for the request. A client is an array of the same objects
+// as in the JSON output, one per currency.
+async fn response(
+    request_line: &str,
+    decimal_places: usize,
+    queries: &mpsc::Sender<Query>,
+) -> (&'static str, String) {
+    let mut parts = request_line.split_whitespace();
+    let (Some(method), Some(path)) = (parts.next(), parts.next()) else {
+        return error(BAD_REQUEST, "malformed request");
+    };
+    let Some(id) = path.strip_prefix("/clients/") else {
+        return error(NOT_FOUND, "unknown path");
+    };
+    if method != "GET" {
+        return error(METHOD_NOT_ALLOWED, "only GET is supported");
+    }
+    let Ok(client) = id.parse() else {
+        return error(BAD_REQUEST, "invalid client ID");
+    };
+
+    let (reply, state) = oneshot::channel();
+    if queries.send(Query { client, reply }).await.is_err() {
+        return error(SERVICE_UNAVAILABLE, "shutting down");
+    }
+    let state = match state.await {
+        Ok(Ok(Some(state))) => state,
+        Ok(Ok(None)) => return error(NOT_FOUND, "unknown client"),
+        Ok(Err(err)) => {
+            tracing::error!(%err, client, "could not query client state");
+            return error(INTERNAL_SERVER_ERROR, "could not query client state");
+        }
+        Err(_) => return error(SERVICE_UNAVAILABLE, "shutting down"),
+    };
+    let with_currency = state.balances().any(|(currency, _)| currency.is_some());
+    match json::OutputRecord::from_client_state(&state, with_currency, decimal_places) {
+        Ok(records) => (
+            OK,
+            serde_json::to_string(&records).expect("records are serializable"),
+        ),
+        Err(err) => error(INTERNAL_SERVER_ERROR, &err.to_string()),
+    }
+}
+
+fn error(status: &'static str, message: &str) -> (&'static str, String) {
+    (status, serde_json::json!({ "error": message }).to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use csv_async::AsyncReaderBuilder;
+    use tx_processor::{InputAmount, StreamProcessor, csv};
+
+    use super::*;
+
+    // Sends the request to a server which knows the clients from `input` and returns the
+    // whole response.
+    async fn request(input: &str, request: &str) -> String {
+        let mut reader = AsyncReaderBuilder::new()
+            .trim(csv_async::Trim::All)
+            .create_deserializer(input.as_bytes());
+        let mut stream_processor = StreamProcessor::new();
+        stream_processor
+            .feed(None, csv::records::<_, InputAmount>(&mut reader))
+            .await;
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (sender, mut queries) = channel();
+        let server = tokio::spawn(listen(listener, 2, sender));
+
+        let mut socket = TcpStream::connect(addr).await.unwrap();
+        socket.write_all(request.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        let read = socket.read_to_string(&mut response);
+        tokio::pin!(read);
+        loop {
+            tokio::select! {
+                result = &mut read => {
+                    result.unwrap();
+                    break;
+                }
+                Some(query) = queries.recv() => {
+                    let state = stream_processor.client_state(query.client).await;
+                    assert!(query.reply.send(state).is_ok(), "should answer query");
+                }
+            }
+        }
+        server.abort();
+        response
+    }
+
+    #[tokio::test]
+    async fn client_state() {
+        let response = request(
+            "type,client,tx,amount\ndeposit,1,1,5.0\ndeposit,1,2,1.0\ndispute,1,2,\n",
+            "GET /clients/1 HTTP/1.1\r\nHost: localhost\r\n\r\n",
+        )
+        .await;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{response}");
+        assert!(
+            response.ends_with(
+                "\r\n\r\n[{\"client\":1,\"available\":\"5.00\",\"held\":\"1.00\",\"total\":\"6.00\",\"locked\":false,\"open_disputes\":1}]"
+            ),
+            "{response}"
+        );
+    }
+
+    #[tokio::test]
+    async fn unknown_client() {
+        let response = request(
+            "type,client,tx,amount\ndeposit,1,1,5.0\n",
+            "GET /clients/2 HTTP/1.1\r\n\r\n",
+        )
+        .await;
+        assert!(
+            response.starts_with("HTTP/1.1 404 Not Found\r\n"),
+            "{response}"
+        );
+    }
+
+    #[tokio::test]
+    async fn invalid_client_id() {
+        let response = request(
+            "type,client,tx,amount\n",
+            "GET /clients/abc HTTP/1.1\r\n\r\n",
+        )
+        .await;
+        assert!(
+            response.starts_with("HTTP/1.1 400 Bad Request\r\n"),
+            "{response}"
+        );
+    }
+
+    #[tokio::test]
+    async fn only_get_is_supported() {
+        let response = request(
+            "type,client,tx,amount\n",
+            "DELETE /clients/1 HTTP/1.1\r\n\r\n",
+        )
+        .await;
+        assert!(
+            response.starts_with("HTTP/1.1 405 Method Not Allowed\r\n"),
+            "{response}"
+        );
+    }
+}
@@ -0,0 +1,38 @@
+//! Final client states written as JSON.
+
+use serde::Serialize;
+
+use crate::{ClientState, csv};
+
+/// The same fields as in the CSV output, plus the number of open disputes.
+#[derive(Debug, Serialize)]
+pub struct OutputRecord {
+    #[serde(flatten)]
+    record: csv::OutputRecord,
+    open_disputes: usize,
+}
+
+impl OutputRecord {
+    /// Creates a record for every currency of the client, same as `csv::OutputRecord`.
+    pub fn from_client_state(
+        client_state: &ClientState,
+        with_currency: bool,
+        decimal_places: usize,
+    ) -> Result<Vec<Self>, csv::OutputError> {
+        let records =
+            csv::OutputRecord::from_client_state(client_state, with_currency, decimal_places)?;
+        Ok(records
+            .into_iter()
+            .map(|record| Self {
+                open_disputes: client_state.open_disputes(record.currency()),
+                record,
+            })
+            .collect())
+    }
+}
+
+impl AsRef<csv::OutputRecord> for OutputRecord {
+    fn as_ref(&self) -> &csv::OutputRecord {
+        &self.record
+    }
+}
@@ -0,0 +1,193 @@
+//! Records read from JSON Lines input, one JSON object per line.
+//!
+//! The objects have the same fields as the columns of the CSV input. Amounts can be given
+//! either as strings or as numbers, the numbers are read exactly as written. Without a
+//! header, the first record with an amount decides whether the amounts name a currency.
+
+use std::future;
+
+use futures_util::{Stream, StreamExt, io::AsyncBufRead};
+use serde::Deserialize;
+use serde_json::Value;
+
+use crate::{
+    InputAmount, ReadError,
+    csv::{CurrencyColumn, InputRecord},
+};
+
+/// Deserializes the input records, remembering the line each of them was read from.
+/// Empty lines are skipped.
+pub fn records<R>(
+    reader: R,
+) -> impl Stream<Item = Result<InputRecord<InputAmount>, ReadError>> + Unpin
+where
+    R: AsyncBufRead + Unpin,
+{
+    records_with(reader, CurrencyColumn::default())
+}
+
+/// Like `records`, but the records must agree with `currency_column`, e.g. when it is
+/// shared with other inputs. A record with an amount which does not agree with it is
+/// rejected, as missing or unexpected currency.
+pub fn records_with<R>(
+    reader: R,
+    currency_column: CurrencyColumn,
+) -> impl Stream<Item = Result<InputRecord<InputAmount>, ReadError>> + Unpin
+where
+    R: AsyncBufRead + Unpin,
+{
+    futures_util::io::AsyncBufReadExt::lines(reader)
+        .enumerate()
+        .filter_map(|(index, line)| future::ready(read(index as u64 + 1, line)))
+        .map(move |record| record.map(|record| record.agree_on_currency(&currency_column)))
+}
+
+// Reads a single line, `None` if it is empty.
+fn read(
+    line_number: u64,
+    line: std::io::Result<String>,
+) -> Option<Result<InputRecord<InputAmount>, ReadError>> {
+    let line = match line {
+        Ok(line) => line,
+        Err(source) => {
+            return Some(Err(ReadError::Io {
+                line: line_number,
+                source,
+            }));
+        }
+    };
+    if line.trim().is_empty() {
+        return None;
+    }
+    let record = serde_json::from_str::<InputRecord<JsonAmount>>(&line)
+        .map(|record| record.map_amount(|amount| amount.0).with_line(line_number))
+        .map_err(|source| ReadError::Json {
+            line: line_number,
+            source,
+        });
+    Some(record)
+}
+
+// JSON numbers can not be read as strings, which is what `InputAmount` expects.
+struct JsonAmount(InputAmount);
+
+impl<'de> Deserialize<'de> for JsonAmount {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        // With `arbitrary_precision` the number keeps its text, so no precision is lost on
+        // the way through `f64`.
+        let text = match Value::deserialize(deserializer)? {
+            Value::String(text) => text,
+            Value::Number(number) => number.to_string(),
+            other => {
+                return Err(serde::de::Error::custom(format!(
+                    "expected a decimal number, found {}",
+                    other
+                )));
+            }
+        };
+        text.parse()
+            .map(Self)
+            .map_err(|err| serde::de::Error::custom(format!("{}: {}", err, text)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_util::StreamExt;
+    use test_case::test_case;
+
+    use super::{records, records_with};
+    use crate::{
+        InputAmount, ReadError,
+        amount::Precision,
+        csv::{self, CurrencyColumn, InputRecord},
+        rejection::Reason,
+    };
+
+    async fn read(input: &str) -> Vec<Result<InputRecord<InputAmount>, ReadError>> {
+        records(input.as_bytes()).collect().await
+    }
+
+    #[test_case(r#"{"type":"deposit","client":1,"tx":2,"amount":"1.5"}"# ; "string amount")]
+    #[test_case(r#"{"type":"deposit","client":1,"tx":2,"amount":1.5}"# ; "number amount")]
+    #[test_case(r#"{"type":"DEPOSIT","client":1,"tx":2,"amount":1.50,"note":"x"}"# ; "unknown field")]
+    #[tokio::test]
+    async fn deposit(input: &str) {
+        let records = read(input).await;
+        let record = records[0].as_ref().unwrap();
+        assert_eq!((record.client(), record.tx(), record.line()), (1, 2, 1));
+        assert_eq!(record.amount(), Some(&"1.5".parse().unwrap()));
+    }
+
+    #[tokio::test]
+    async fn number_keeps_precision() {
+        let input = r#"{"type":"deposit","client":1,"tx":2,"amount":922337203685477.5807}"#;
+        let records = read(input).await;
+        let record = records[0].as_ref().unwrap();
+        assert_eq!(
+            record.amount(),
+            Some(&"922337203685477.5807".parse().unwrap())
+        );
+    }
+
+    #[tokio::test]
+    async fn lines_are_counted_with_empty_ones() {
+        let input = "{\"type\":\"dispute\",\"client\":1,\"tx\":1}\n\n{\"type\":\"bogus\"}\n";
+        let records = read(input).await;
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].as_ref().unwrap().line(), 1);
+        assert_eq!(records[1].as_ref().unwrap_err().line(), Some(3));
+    }
+
+    #[test_case(r#"{"type":"deposit","client":1,"tx":2,"amount":true}"# ; "boolean amount")]
+    #[test_case(r#"{"type":"deposit","client":1,"tx":2,"amount":"1e3"}"# ; "exponent")]
+    #[test_case(r#"{"type":"deposit","client":1"# ; "truncated")]
+    #[tokio::test]
+    async fn malformed(input: &str) {
+        let records = read(input).await;
+        assert!(matches!(records[0], Err(ReadError::Json { line: 1, .. })));
+    }
+
+    // The first record with an amount decides, the dispute in between has none.
+    #[test_case("", r#","currency":"EUR""#, Reason::UnexpectedCurrency ; "unexpected currency")]
+    #[test_case(r#","currency":"EUR""#, "", Reason::MissingCurrency ; "missing currency")]
+    #[tokio::test]
+    async fn currencies_must_agree(first: &str, second: &str, reason: Reason) {
+        let input = format!(
+            "{{\"type\":\"deposit\",\"client\":1,\"tx\":1,\"amount\":1{first}}}\n\
+             {{\"type\":\"dispute\",\"client\":1,\"tx\":1}}\n\
+             {{\"type\":\"deposit\",\"client\":1,\"tx\":2,\"amount\":1{second}}}\n"
+        );
+        let reasons: Vec<_> = read(&input)
+            .await
+            .into_iter()
+            .map(|record| {
+                let result = record.unwrap().into_transaction(Precision::default());
+                result.err().as_ref().map(Reason::from)
+            })
+            .collect();
+        assert_eq!(reasons, [None, None, Some(reason)]);
+    }
+
+    #[tokio::test]
+    async fn currency_column_is_shared() {
+        let currency_column = CurrencyColumn::default();
+        let first = r#"{"type":"deposit","client":1,"tx":1,"amount":1,"currency":"EUR"}"#;
+        let second = r#"{"type":"deposit","client":1,"tx":2,"amount":1}"#;
+        let _: Vec<_> = records_with(first.as_bytes(), currency_column.clone())
+            .collect()
+            .await;
+        let records: Vec<_> = records_with(second.as_bytes(), currency_column.clone())
+            .collect()
+            .await;
+        let record = records.into_iter().next().unwrap().unwrap();
+        assert!(matches!(
+            record.into_transaction(Precision::default()),
+            Err(csv::Error::AmountMustHaveCurrency)
+        ));
+        assert_eq!(currency_column.present(), Some(true));
+    }
+}
@@ -0,0 +1,63 @@
+//! Engine of the transaction processor.
+//!
+//! A `StreamProcessor` applies a stream of `csv::InputRecord`s to the client accounts and
+//! produces the final `ClientState` of every client. The `tx_processor` binary is a thin
+//! wrapper which reads the records from a CSV file and writes the states as `csv::OutputRecord`s,
+//! services can embed the same engine in-process.
+//!
+//! Only the items exported here are part of the public API, everything else may change.
+//!
+//! ```
+//! use futures_util::StreamExt;
+//! use tx_processor::{InputAmount, StreamProcessor, csv};
+//!
+//! # #[tokio::main]
+//! # async fn main() {
+//! let input = "type,client,tx,amount\ndeposit,1,1,1.5\nwithdrawal,1,2,0.5\n";
+//! let mut reader = csv_async::AsyncReaderBuilder::new()
+//!     .trim(csv_async::Trim::All)
+//!     .create_deserializer(input.as_bytes());
+//! let mut records = csv::records::<_, InputAmount>(&mut reader);
+//!
+//! let mut processor = StreamProcessor::new();
+//! let states: Vec<_> = processor.process(&mut records).await.collect().await;
+//! let state = states[0].as_ref().unwrap();
+//! let (_, balances) = state.balances().next().unwrap();
+//! assert_eq!(balances.available().fixed(2).to_string(), "1.00");
+//! # }
+//! ```
+
+mod amount;
+mod balances;
+mod checked_decimal;
+mod client_processor;
+pub mod csv;
+mod currency;
+pub mod db;
+mod error;
+pub mod json;
+pub mod jsonl;
+pub mod outcome;
+mod read_error;
+pub mod recovery;
+pub mod rejection;
+pub mod report;
+mod shard;
+pub mod snapshot;
+mod stream_processor;
+#[cfg(test)]
+mod tests;
+mod transaction;
+
+pub use amount::{Amount, ExcessPrecision, Fixed, InputAmount, ParseAmountError, Precision};
+pub use balances::Balances;
+pub use checked_decimal::{NonNegative, NonZero};
+pub use client_processor::{AUDIT_TARGET, ClientState, WithdrawalDisputePolicy};
+pub use currency::Currency;
+pub use db::DepositValueCache;
+pub use read_error::ReadError;
+pub use stream_processor::{ClientResult, Error, StreamProcessor};
+pub use transaction::{
+    Chargeback, Credit, Deposit, Dispute, Resolve, Transaction, TransactionPayload, Transfer,
+    Unlock, Withdrawal,
+};
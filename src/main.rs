@@ -1,65 +1,320 @@
-use std::env;
+use std::{path::Path, sync::Mutex};
 
-use balances::{BalanceUpdater, Balances};
-use checked_decimal::{NonNegative, NonZero};
-use client_processor::ClientProcessor;
-use csv_async::{AsyncReaderBuilder, AsyncSerializer};
-use db::in_mem;
+use clap::Parser;
+use csv_async::AsyncReaderBuilder;
 use futures_util::StreamExt;
-use rust_decimal::Decimal;
-use stream_processor::StreamProcessor;
-use tokio::fs::File;
+use tokio::{fs::File, io::AsyncWrite, net::TcpListener};
 use tokio_util::compat::{TokioAsyncReadCompatExt, TokioAsyncWriteCompatExt};
+use tracing_subscriber::{EnvFilter, filter::LevelFilter};
+use tx_processor::{
+    InputAmount, StreamProcessor, csv, jsonl, outcome, recovery, rejection, snapshot,
+};
 
-mod balances;
-mod checked_decimal;
-mod client_processor;
-mod csv;
-mod db;
-mod error;
-mod stream_processor;
-#[cfg(test)]
-mod tests;
-mod transaction;
+mod cli;
+mod compression;
+mod http;
+mod output;
+mod serve;
 
 // `anyhow` only used in the main module for easier integration between
 // operating system and ?-based error handling.
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    // No need to add dedicated dependency (like 'clap') because we only have a single arg.
-    let args: Vec<String> = env::args().collect();
-    if args.len() < 2 {
-        eprintln!("Usage: {} <input_file>", args[0]);
-        std::process::exit(1);
-    }
-    let filename = &args[1];
+    let args = cli::Args::parse();
+    init_tracing(args.log_file.as_deref())?;
 
-    let file = File::open(filename).await?.compat();
-    let mut csv_reader = AsyncReaderBuilder::new()
-        .has_headers(true)
-        .trim(csv_async::Trim::All)
-        .create_deserializer(file);
-    let mut input = csv_reader.deserialize::<csv::InputRecord<Decimal>>();
+    let output: Box<dyn AsyncWrite + Unpin + Send> = match &args.output {
+        Some(path) => Box::new(File::create(path).await?),
+        None => Box::new(tokio::io::stdout()),
+    };
 
-    let mut stream_processor = StreamProcessor::new();
-    let mut results = stream_processor.process(&mut input).await;
+    let precision = args.precision();
+    let mut stream_processor = StreamProcessor::new()
+        .with_backend(args.backend()?)
+        .with_precision(precision)
+        .with_withdrawal_dispute_policy(args.withdrawal_dispute_policy());
+    if let Some(shards) = args.shards {
+        stream_processor = stream_processor.with_shard_count(shards);
+    }
+    // All inputs must agree on whether the amounts have currencies, since this decides
+    // the columns of the output. So must the balances of an imported snapshot.
+    let mut currency_column = csv::CurrencyColumn::default();
+    // A checkpoint of a resumed run already contains the clients of the snapshot and
+    // replaces them.
+    if let Some(path) = &args.import_snapshot {
+        let snapshot = snapshot::Snapshot::read(path)?;
+        tracing::info!(clients = snapshot.client_count(), "snapshot imported");
+        currency_column = snapshot.currency_column();
+        stream_processor = stream_processor.with_snapshot(snapshot)?;
+    }
+    // A resumed run keeps the reports up to its checkpoint, nothing without one.
+    let mut resumed_reports = None;
+    if let Some(dir) = &args.recovery_dir {
+        let (recovery, checkpoint) = match args.resume {
+            true => recovery::RecoveryDir::resume(dir)?,
+            false => (recovery::RecoveryDir::create(dir)?, None),
+        };
+        if args.resume {
+            resumed_reports = Some(
+                checkpoint
+                    .as_ref()
+                    .map(recovery::Checkpoint::reports)
+                    .unwrap_or_default(),
+            );
+        }
+        stream_processor = stream_processor.with_recovery(recovery, args.checkpoint_every);
+        if let Some(checkpoint) = checkpoint {
+            stream_processor = stream_processor.with_checkpoint(checkpoint)?;
+        }
+    }
+    let rejections_writer = match &args.rejections {
+        Some(path) => {
+            let kept = resumed_reports.map(|reports| reports.rejections().unwrap_or(0));
+            let (file, len) = open_report(path, kept).await?;
+            let (sender, receiver) = rejection::channel();
+            stream_processor = stream_processor.with_rejections(sender);
+            Some(tokio::spawn(rejection::write_csv(
+                receiver,
+                file.compat_write(),
+                len,
+            )))
+        }
+        None => None,
+    };
+    let outcomes_writer = match &args.outcomes {
+        Some(path) => {
+            let kept = resumed_reports.map(|reports| reports.outcomes().unwrap_or(0));
+            let (file, len) = open_report(path, kept).await?;
+            let (sender, receiver) = outcome::channel();
+            stream_processor = stream_processor.with_outcomes(sender);
+            Some(tokio::spawn(outcome::write_csv(
+                receiver,
+                file.compat_write(),
+                len,
+                precision.decimal_places(),
+            )))
+        }
+        None => None,
+    };
 
-    let mut writer = AsyncSerializer::from_writer(tokio::io::stdout().compat_write());
+    if let Some(addr) = args.serve {
+        let listener = TcpListener::bind(addr).await?;
+        tracing::info!(%addr, "accepting connections");
+        let shutdown = async {
+            if let Err(err) = tokio::signal::ctrl_c().await {
+                tracing::error!(%err, "could not wait for Ctrl-C");
+                std::future::pending::<()>().await;
+            }
+        };
+        let (query_sender, queries) = http::channel();
+        let http = match args.http {
+            Some(addr) => {
+                let listener = TcpListener::bind(addr).await?;
+                tracing::info!(%addr, "answering HTTP requests");
+                Some(tokio::spawn(http::listen(
+                    listener,
+                    precision.decimal_places(),
+                    query_sender,
+                )))
+            }
+            None => None,
+        };
+        serve::serve(
+            listener,
+            args.input_format,
+            &mut stream_processor,
+            &currency_column,
+            queries,
+            shutdown,
+        )
+        .await?;
+        if let Some(http) = http {
+            http.abort();
+        }
+    }
+    for input in &args.inputs()? {
+        let file = input.open().await?.compat();
+        if args.input_format == cli::InputFormat::Jsonl {
+            let mut records = jsonl::records_with(
+                futures_util::io::BufReader::new(file),
+                currency_column.clone(),
+            );
+            stream_processor
+                .feed(Some(input.name().into()), &mut records)
+                .await;
+            continue;
+        }
+        let mut csv_reader = AsyncReaderBuilder::new()
+            .has_headers(true)
+            .trim(csv_async::Trim::All)
+            .create_deserializer(file);
+        let has_currency = csv::has_currency_column(&mut csv_reader).await?;
+        if !currency_column.agrees(has_currency) {
+            anyhow::bail!(
+                "{} does not match the currency column of the previous inputs or the snapshot",
+                input.name()
+            );
+        }
+        let mut records = csv::records::<_, InputAmount>(&mut csv_reader);
+        stream_processor
+            .feed(Some(input.name().into()), &mut records)
+            .await;
+    }
+    if let Some(path) = &args.export_snapshot {
+        stream_processor.snapshot().await?.write(path)?;
+    }
+    let results = stream_processor.finish().await;
+    let currency_present = currency_column.present();
+    let (with_currency, mut results) = match currency_present {
+        Some(with_currency) if args.sort_by == cli::SortBy::Client => {
+            (with_currency, results.boxed())
+        }
+        // Without inputs with amounts or an imported snapshot with balances, the currencies
+        // are only known from the balances, if any. Other orders need all states anyway.
+        _ => {
+            let states: Vec<_> = results.collect().await;
+            let with_currency = currency_present.unwrap_or_else(|| {
+                states
+                    .iter()
+                    .flatten()
+                    .any(|state| state.balances().any(|(currency, _)| currency.is_some()))
+            });
+            (with_currency, futures_util::stream::iter(states).boxed())
+        }
+    };
+
+    let mut writer = output::Writer::new(args.output_format, output.compat_write());
+    let mut sorted = Vec::new();
     while let Some(client_state) = results.next().await {
         match client_state {
             Ok(client_state) => {
-                let Ok(record): Result<csv::OutputRecord, _> = client_state.try_into() else {
-                    //tracing::error!(%_err);
-                    continue;
-                };
-                writer.serialize(&record).await?;
+                let records =
+                    output::records(&client_state, with_currency, precision.decimal_places());
+                match args.sort_by {
+                    // Results already come ordered by the client.
+                    cli::SortBy::Client => writer.write(&records).await?,
+                    cli::SortBy::TotalDesc => sorted.extend(records),
+                }
             }
-            Err(_err) => {
-                //tracing::error!(%_err);
+            Err(err) => {
+                tracing::error!(%err, "could not receive client states");
             }
         }
     }
-    writer.flush().await?;
+    sorted.sort_by(|a, b| {
+        let (a, b): (&csv::OutputRecord, &csv::OutputRecord) = (a.as_ref(), b.as_ref());
+        b.total()
+            .cmp(&a.total())
+            .then(a.client().cmp(&b.client()))
+            .then(a.currency().cmp(&b.currency()))
+    });
+    writer.write(&sorted).await?;
+    writer.finish().await?;
+
+    // Rejections and outcomes are written until the last sender, owned by the stream processor,
+    // is dropped.
+    drop(results);
+    drop(stream_processor);
+    if let Some(rejections_writer) = rejections_writer {
+        rejections_writer.await??;
+    }
+    if let Some(outcomes_writer) = outcomes_writer {
+        outcomes_writer.await??;
+    }
 
     Ok(())
 }
+
+// Opens the file for the rejections or the outcomes. A resumed run keeps the first `kept`
+// bytes of the interrupted one, the rows of the records up to the checkpoint, and appends
+// after them; the rows of the records fed again are cut off. Returns the length kept.
+async fn open_report(path: &Path, kept: Option<u64>) -> std::io::Result<(File, u64)> {
+    let Some(kept) = kept else {
+        return Ok((File::create(path).await?, 0));
+    };
+    let file = tokio::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .await?;
+    let len = kept.min(file.metadata().await?.len());
+    file.set_len(len).await?;
+    Ok((file, len))
+}
+
+// Logs are controlled with the `RUST_LOG` environment variable and only errors and audit
+// events are logged by default. They never go to `stdout`, which is reserved for the results.
+fn init_tracing(log_file: Option<&Path>) -> std::io::Result<()> {
+    let mut filter = EnvFilter::builder()
+        .with_default_directive(LevelFilter::ERROR.into())
+        .from_env_lossy();
+    let rust_log = std::env::var(EnvFilter::DEFAULT_ENV).unwrap_or_default();
+    if logs_audit_by_default(&rust_log) {
+        let audit = format!("{}=info", tx_processor::AUDIT_TARGET)
+            .parse()
+            .expect("audit directive is valid");
+        filter = filter.add_directive(audit);
+    }
+    let subscriber = tracing_subscriber::fmt().with_env_filter(filter);
+    match log_file {
+        Some(path) => {
+            let file = std::fs::File::create(path)?;
+            subscriber
+                .with_ansi(false)
+                .with_writer(Mutex::new(file))
+                .init();
+        }
+        None => subscriber.with_writer(std::io::stderr).init(),
+    }
+    Ok(())
+}
+
+// The audit events are logged unless `RUST_LOG` configures their target itself, or turns
+// all logs off.
+fn logs_audit_by_default(rust_log: &str) -> bool {
+    !rust_log.split(',').map(str::trim).any(|directive| {
+        let target = directive.split(['=', '[']).next().unwrap_or_default();
+        target == tx_processor::AUDIT_TARGET || directive.eq_ignore_ascii_case("off")
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use test_case::test_case;
+
+    use super::{logs_audit_by_default, open_report};
+
+    #[test_case("", true ; "unset")]
+    #[test_case("debug", true ; "global level")]
+    #[test_case("auditor=off", true ; "other target")]
+    #[test_case("off", false ; "off")]
+    #[test_case("info,audit=off", false ; "audit off")]
+    #[test_case("audit[span]=warn", false ; "audit with span")]
+    fn audit_default(rust_log: &str, expected: bool) {
+        assert_eq!(logs_audit_by_default(rust_log), expected);
+    }
+
+    #[test_case(None, "file,line\na,1\n", "" ; "new run")]
+    #[test_case(Some(0), "", "" ; "resumed without earlier file")]
+    #[test_case(Some(0), "file,line\na,1\n", "" ; "resumed without checkpoint")]
+    #[test_case(Some(10), "file,line\na,1\na,2\n", "file,line\n" ; "resumed")]
+    #[test_case(Some(14), "file,line\n", "file,line\n" ; "resumed after lost rows")]
+    #[tokio::test]
+    async fn report(kept: Option<u64>, earlier: &str, expected: &str) {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("rejections.csv");
+        if !earlier.is_empty() {
+            std::fs::write(&path, earlier).unwrap();
+        }
+        let (mut file, len) = open_report(&path, kept).await.unwrap();
+        tokio::io::AsyncWriteExt::write_all(&mut file, b"x\n")
+            .await
+            .unwrap();
+        tokio::io::AsyncWriteExt::flush(&mut file).await.unwrap();
+        assert_eq!(len, expected.len() as u64);
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            format!("{expected}x\n")
+        );
+    }
+}
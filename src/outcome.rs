@@ -0,0 +1,199 @@
+//! Outcomes acknowledge every transaction handled by a client processor, applied or not,
+//! together with the balances right after it.
+//!
+//! They are optionally collected by the shards, e.g. for a downstream ledger that needs
+//! an acknowledgement of every transaction rather than only the final client states.
+//! Records which never reach a client processor, because they are malformed or invalid,
+//! are only reported as rejections.
+
+use std::sync::Arc;
+
+use futures_util::io::AsyncWrite;
+use serde::Serialize;
+
+use crate::{
+    Balances, Fixed,
+    csv::Kind,
+    currency::Currency,
+    rejection::Reason,
+    report::{self, CsvWriter},
+};
+
+const OUTCOME_CHANNEL_SIZE: usize = 10_000;
+
+/// Whether the transaction was applied.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[non_exhaustive]
+#[serde(rename_all = "snake_case")]
+pub enum Status {
+    Applied,
+    Rejected,
+}
+
+/// A single transaction handled by a client processor. Both sides of a transfer have their
+/// own outcome, as does the refund of a transfer that could not be credited.
+#[derive(Debug, Clone)]
+pub struct Outcome {
+    file: Option<Arc<str>>,
+    line: u64,
+    kind: Kind,
+    client: u16,
+    tx: u32,
+    reason: Option<Reason>,
+    currency: Option<Currency>,
+    balances: Balances,
+    locked: bool,
+    // Who authorised an unlock.
+    authorised_by: Option<String>,
+}
+
+impl Outcome {
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn new(
+        file: Option<Arc<str>>,
+        line: u64,
+        kind: Kind,
+        client: u16,
+        tx: u32,
+        reason: Option<Reason>,
+        currency: Option<Currency>,
+        balances: Balances,
+        locked: bool,
+    ) -> Self {
+        Self {
+            file,
+            line,
+            kind,
+            client,
+            tx,
+            reason,
+            currency,
+            balances,
+            locked,
+            authorised_by: None,
+        }
+    }
+
+    pub(crate) fn with_authorised_by(mut self, authorised_by: Option<String>) -> Self {
+        self.authorised_by = authorised_by;
+        self
+    }
+
+    /// Name of the input the transaction was read from.
+    pub fn file(&self) -> Option<&str> {
+        self.file.as_deref()
+    }
+
+    pub fn line(&self) -> u64 {
+        self.line
+    }
+
+    pub fn kind(&self) -> Kind {
+        self.kind
+    }
+
+    pub fn client(&self) -> u16 {
+        self.client
+    }
+
+    pub fn tx(&self) -> u32 {
+        self.tx
+    }
+
+    pub fn status(&self) -> Status {
+        match self.reason {
+            Some(_) => Status::Rejected,
+            None => Status::Applied,
+        }
+    }
+
+    /// Why the transaction was rejected, `None` if it was applied.
+    pub fn reason(&self) -> Option<Reason> {
+        self.reason
+    }
+
+    /// Currency of the balances. For disputes, resolves and chargebacks it is the currency
+    /// of the original transaction, `None` if that is unknown.
+    pub fn currency(&self) -> Option<Currency> {
+        self.currency
+    }
+
+    /// Balances of the client in the currency, right after the transaction.
+    pub fn balances(&self) -> &Balances {
+        &self.balances
+    }
+
+    /// Whether the account is locked right after the transaction, e.g. by a chargeback.
+    pub fn locked(&self) -> bool {
+        self.locked
+    }
+
+    /// Who authorised the unlock, `None` for the other transactions.
+    pub fn authorised_by(&self) -> Option<&str> {
+        self.authorised_by.as_deref()
+    }
+}
+
+// A row of the CSV file, with the balances displayed with a fixed number of decimal places.
+#[derive(Serialize)]
+struct Row<'a> {
+    file: Option<&'a str>,
+    line: u64,
+    #[serde(rename = "type")]
+    kind: Kind,
+    client: u16,
+    tx: u32,
+    status: Status,
+    reason: Option<Reason>,
+    currency: Option<Currency>,
+    available: Fixed,
+    held: Fixed,
+    // Empty if the total overflows.
+    total: Option<Fixed>,
+    locked: bool,
+    authorised_by: Option<&'a str>,
+}
+
+impl<'a> Row<'a> {
+    fn new(outcome: &'a Outcome, decimal_places: usize) -> Self {
+        let balances = outcome.balances();
+        Self {
+            file: outcome.file(),
+            line: outcome.line,
+            kind: outcome.kind,
+            client: outcome.client,
+            tx: outcome.tx,
+            status: outcome.status(),
+            reason: outcome.reason,
+            currency: outcome.currency,
+            available: balances.available().fixed(decimal_places),
+            held: balances.held().fixed(decimal_places),
+            total: balances.total().map(|total| total.fixed(decimal_places)),
+            locked: outcome.locked,
+            authorised_by: outcome.authorised_by(),
+        }
+    }
+}
+
+pub fn channel() -> (report::Sender<Outcome>, report::Receiver<Outcome>) {
+    report::channel(OUTCOME_CHANNEL_SIZE)
+}
+
+/// Writes the outcomes as CSV until all senders are dropped, after the `len` bytes already
+/// in the file. The balances have `decimal_places`. The header is only written to an empty
+/// file.
+pub async fn write_csv<W>(
+    mut receiver: report::Receiver<Outcome>,
+    writer: W,
+    len: u64,
+    decimal_places: usize,
+) -> Result<(), csv_async::Error>
+where
+    W: AsyncWrite + Unpin,
+{
+    let mut writer = CsvWriter::new(writer, len);
+    while let Some(outcome) = writer.next(&mut receiver).await? {
+        writer.serialize(Row::new(&outcome, decimal_places)).await?;
+    }
+    writer.finish().await
+}
@@ -0,0 +1,88 @@
+//! Writers of the final client states in the supported output formats.
+
+use std::io;
+
+use csv_async::AsyncSerializer;
+use futures_util::io::{AsyncWrite, AsyncWriteExt};
+use tx_processor::{ClientState, json};
+
+use crate::cli::OutputFormat;
+
+/// Creates the records of a single client. A client whose state can not be written
+/// is logged and skipped.
+pub(super) fn records(
+    client_state: &ClientState,
+    with_currency: bool,
+    decimal_places: usize,
+) -> Vec<json::OutputRecord> {
+    json::OutputRecord::from_client_state(client_state, with_currency, decimal_places)
+        .unwrap_or_else(|err| {
+            let client = client_state.client();
+            tracing::error!(%err, client, "could not output client state");
+            Vec::new()
+        })
+}
+
+pub(super) enum Writer<W>
+where
+    W: AsyncWrite + Unpin,
+{
+    // Boxed, since the serializer is much larger than the plain writers.
+    Csv(Box<AsyncSerializer<W>>),
+    // A single array. The brackets are written around the first and after the last record.
+    Json { writer: W, empty: bool },
+    Jsonl(W),
+}
+
+impl<W> Writer<W>
+where
+    W: AsyncWrite + Unpin,
+{
+    pub(super) fn new(format: OutputFormat, writer: W) -> Self {
+        match format {
+            OutputFormat::Csv => Self::Csv(Box::new(AsyncSerializer::from_writer(writer))),
+            OutputFormat::Json => Self::Json {
+                writer,
+                empty: true,
+            },
+            OutputFormat::Jsonl => Self::Jsonl(writer),
+        }
+    }
+
+    /// Writes the records. The CSV output leaves out the number of open disputes.
+    pub(super) async fn write(&mut self, records: &[json::OutputRecord]) -> io::Result<()> {
+        for record in records {
+            match self {
+                Self::Csv(writer) => {
+                    let record: &tx_processor::csv::OutputRecord = record.as_ref();
+                    writer.serialize(record).await.map_err(io::Error::other)?;
+                }
+                Self::Json { writer, empty } => {
+                    writer
+                        .write_all(if *empty { b"[\n" } else { b",\n" })
+                        .await?;
+                    *empty = false;
+                    writer.write_all(&serde_json::to_vec(record)?).await?;
+                }
+                Self::Jsonl(writer) => {
+                    writer.write_all(&serde_json::to_vec(record)?).await?;
+                    writer.write_all(b"\n").await?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub(super) async fn finish(self) -> io::Result<()> {
+        match self {
+            Self::Csv(mut writer) => writer.flush().await.map_err(io::Error::other),
+            Self::Json { mut writer, empty } => {
+                writer
+                    .write_all(if empty { b"[]\n" } else { b"\n]\n" })
+                    .await?;
+                writer.flush().await
+            }
+            Self::Jsonl(mut writer) => writer.flush().await,
+        }
+    }
+}
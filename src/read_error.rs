@@ -0,0 +1,28 @@
+//! Errors of reading the input records, regardless of the input format.
+
+use thiserror::Error;
+
+/// A record which could not be read. The processing continues with the next one.
+#[derive(Debug, Error)]
+#[non_exhaustive]
+pub enum ReadError {
+    #[error(transparent)]
+    Csv(#[from] csv_async::Error),
+    #[error("invalid JSON on line {line}: {source}")]
+    Json {
+        line: u64,
+        source: serde_json::Error,
+    },
+    #[error("could not read line {line}: {source}")]
+    Io { line: u64, source: std::io::Error },
+}
+
+impl ReadError {
+    /// Line of the input, if it is known.
+    pub fn line(&self) -> Option<u64> {
+        match self {
+            Self::Csv(err) => err.position().map(|position| position.line()),
+            Self::Json { line, .. } | Self::Io { line, .. } => Some(*line),
+        }
+    }
+}
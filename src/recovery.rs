@@ -0,0 +1,332 @@
+//! Crash recovery: checkpoints of the state, and an audit log of the applied transactions.
+//!
+//! Both live in a single directory. Periodically, the stream processor captures every
+//! client in a checkpoint together with the position in the inputs and the lengths of the
+//! audit log and the reports at that point. A resumed run restores the last checkpoint, cuts
+//! off the audit log entries written after it and skips the input records which were already
+//! fed. The reports are cut back to the checkpoint by their writer, see
+//! `Checkpoint::reports`.
+//!
+//! The audit log is never read back, the checkpoint alone is enough to resume. It records
+//! what was applied, e.g. for a later reconciliation, and stays consistent with the
+//! checkpoints across resumed runs.
+//!
+//! Only the in-memory deposit cache can be checkpointed.
+
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, BufWriter, Write},
+    path::{Path, PathBuf},
+    sync::Arc,
+    thread,
+};
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::{mpsc, oneshot};
+
+use crate::{
+    amount::Amount,
+    currency::Currency,
+    snapshot::{ClientSnapshot, validate, write_atomically},
+    transaction::Transaction,
+};
+
+const AUDIT_LOG_FILE: &str = "audit_log.jsonl";
+const CHECKPOINT_FILE: &str = "checkpoint.json";
+
+// Entries and checkpoints queued for the writer thread.
+const REQUEST_CHANNEL_SIZE: usize = 10_000;
+
+// Checkpoints with another version are refused rather than misread.
+const CHECKPOINT_VERSION: u32 = 1;
+
+/// State of all clients after the records up to a position in the inputs.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Checkpoint {
+    version: u32,
+    // Number of inputs fed completely.
+    inputs: usize,
+    // Last line fed from the input after them, `0` if none.
+    line: u64,
+    // Bytes of the audit log written up to the checkpoint.
+    audit_log_len: u64,
+    // Missing in checkpoints without any reports.
+    #[serde(default)]
+    reports: ReportLengths,
+    clients: Vec<ClientSnapshot>,
+}
+
+impl Checkpoint {
+    pub(crate) fn new(
+        inputs: usize,
+        line: u64,
+        audit_log_len: u64,
+        reports: ReportLengths,
+        clients: Vec<ClientSnapshot>,
+    ) -> Self {
+        Self {
+            version: CHECKPOINT_VERSION,
+            inputs,
+            line,
+            audit_log_len,
+            reports,
+            clients,
+        }
+    }
+
+    /// Number of inputs which were fed completely, and are skipped when resuming.
+    pub fn inputs(&self) -> usize {
+        self.inputs
+    }
+
+    /// Last line fed from the next input, the lines up to it are skipped when resuming.
+    pub fn line(&self) -> u64 {
+        self.line
+    }
+
+    /// Lengths of the reports up to the checkpoint, a resumed run cuts them back to these.
+    pub fn reports(&self) -> ReportLengths {
+        self.reports
+    }
+
+    pub(crate) fn into_clients(self) -> Vec<ClientSnapshot> {
+        self.clients
+    }
+}
+
+/// Bytes of the rejections and the outcomes written up to a checkpoint, `None` for the
+/// reports which were not written.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ReportLengths {
+    rejections: Option<u64>,
+    outcomes: Option<u64>,
+}
+
+impl ReportLengths {
+    pub(crate) fn new(rejections: Option<u64>, outcomes: Option<u64>) -> Self {
+        Self {
+            rejections,
+            outcomes,
+        }
+    }
+
+    pub fn rejections(&self) -> Option<u64> {
+        self.rejections
+    }
+
+    pub fn outcomes(&self) -> Option<u64> {
+        self.outcomes
+    }
+}
+
+/// A single applied transaction. The credited side of a transfer is a `credit`.
+#[derive(Debug, Serialize)]
+pub(crate) struct Entry {
+    file: Option<Arc<str>>,
+    line: u64,
+    #[serde(rename = "type")]
+    kind: &'static str,
+    client: u16,
+    tx: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    amount: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    currency: Option<Currency>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    authorised_by: Option<String>,
+}
+
+impl Entry {
+    pub(crate) fn new(file: Option<Arc<str>>, line: u64, tx: &Transaction) -> Self {
+        let (kind, amount, currency) = match tx {
+            Transaction::Deposit(tx) => ("deposit", Some(*tx.amount()), tx.currency()),
+            Transaction::Withdrawal(tx) => ("withdrawal", Some(*tx.amount()), tx.currency()),
+            Transaction::Dispute(_) => ("dispute", None, None),
+            Transaction::Resolve(_) => ("resolve", None, None),
+            Transaction::Chargeback(_) => ("chargeback", None, None),
+            Transaction::Transfer(tx) => ("transfer", Some(*tx.amount()), tx.currency()),
+            Transaction::Credit(tx) => ("credit", Some(*tx.amount()), tx.currency()),
+            Transaction::Unlock(_) => ("unlock", None, None),
+        };
+        Self {
+            file,
+            line,
+            kind,
+            client: tx.client(),
+            tx: tx.tx(),
+            amount: amount.map(|amount| Amount::from(amount).to_string()),
+            currency,
+            authorised_by: match tx {
+                Transaction::Unlock(tx) => Some(tx.authorised_by().to_owned()),
+                _ => None,
+            },
+        }
+    }
+}
+
+/// The audit log and the checkpoints in a directory. The files are written by a dedicated
+/// thread, so that the shards do not block on the disk. Clones share it.
+#[derive(Debug, Clone)]
+pub struct RecoveryDir {
+    requests: mpsc::Sender<Request>,
+}
+
+impl RecoveryDir {
+    /// Starts from scratch in `dir`, which is created if needed. A previous audit log and
+    /// checkpoint are discarded.
+    pub fn create<P: AsRef<Path>>(dir: P) -> io::Result<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)?;
+        match fs::remove_file(dir.join(CHECKPOINT_FILE)) {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err),
+            _ => (),
+        }
+        Self::open(dir, 0)
+    }
+
+    /// Continues in `dir` from the last checkpoint, if there is one. The audit log entries
+    /// written after the checkpoint are discarded, since their records are fed again.
+    pub fn resume<P: AsRef<Path>>(dir: P) -> io::Result<(Self, Option<Checkpoint>)> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)?;
+        let checkpoint = match fs::read(dir.join(CHECKPOINT_FILE)) {
+            Ok(bytes) => {
+                let checkpoint: Checkpoint = serde_json::from_slice(&bytes)?;
+                if checkpoint.version != CHECKPOINT_VERSION {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("unsupported checkpoint version {}", checkpoint.version),
+                    ));
+                }
+                validate(&checkpoint.clients)?;
+                Some(checkpoint)
+            }
+            Err(err) if err.kind() == io::ErrorKind::NotFound => None,
+            Err(err) => return Err(err),
+        };
+        let audit_log_len = checkpoint
+            .as_ref()
+            .map_or(0, |checkpoint| checkpoint.audit_log_len);
+        Ok((Self::open(dir, audit_log_len)?, checkpoint))
+    }
+
+    // Opens the audit log for appending after the first `len` bytes and starts the thread
+    // writing it.
+    fn open(dir: PathBuf, len: u64) -> io::Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .append(true)
+            .open(dir.join(AUDIT_LOG_FILE))?;
+        file.set_len(len)?;
+        let (requests, receiver) = mpsc::channel(REQUEST_CHANNEL_SIZE);
+        let writer = Writer {
+            dir,
+            audit_log: BufWriter::new(file),
+        };
+        thread::Builder::new()
+            .name("recovery".into())
+            .spawn(move || writer.run(receiver))?;
+        Ok(Self { requests })
+    }
+
+    /// Appends the entry to the audit log. It is buffered until the next checkpoint.
+    pub(crate) async fn append(&self, entry: Entry) -> io::Result<()> {
+        self.requests
+            .send(Request::Append(entry))
+            .await
+            .map_err(|_| stopped())
+    }
+
+    /// Writes the checkpoint, once the audit log up to it is safely on disk. Its length is
+    /// taken at this point, so no entries may be appended meanwhile.
+    pub(crate) async fn checkpoint(
+        &self,
+        inputs: usize,
+        line: u64,
+        reports: ReportLengths,
+        clients: Vec<ClientSnapshot>,
+    ) -> io::Result<()> {
+        let (reply, written) = oneshot::channel();
+        self.requests
+            .send(Request::Checkpoint {
+                inputs,
+                line,
+                reports,
+                clients,
+                reply,
+            })
+            .await
+            .map_err(|_| stopped())?;
+        written.await.map_err(|_| stopped())?
+    }
+}
+
+fn stopped() -> io::Error {
+    io::Error::other("the recovery writer has stopped")
+}
+
+#[derive(Debug)]
+enum Request {
+    Append(Entry),
+    Checkpoint {
+        inputs: usize,
+        line: u64,
+        reports: ReportLengths,
+        clients: Vec<ClientSnapshot>,
+        reply: oneshot::Sender<io::Result<()>>,
+    },
+}
+
+// Owns the files, on its own thread. The requests are handled in the order they are sent,
+// so a checkpoint covers all entries appended before it.
+struct Writer {
+    dir: PathBuf,
+    audit_log: BufWriter<File>,
+}
+
+impl Writer {
+    fn run(mut self, mut requests: mpsc::Receiver<Request>) {
+        while let Some(request) = requests.blocking_recv() {
+            match request {
+                Request::Append(entry) => {
+                    if let Err(err) = self.append(&entry) {
+                        tracing::error!(%err, "failed to append to the audit log");
+                    }
+                }
+                Request::Checkpoint {
+                    inputs,
+                    line,
+                    reports,
+                    clients,
+                    reply,
+                } => {
+                    // Nobody waits for it any more if the stream processor is gone.
+                    let _ = reply.send(self.checkpoint(inputs, line, reports, clients));
+                }
+            }
+        }
+        if let Err(err) = self.audit_log.flush() {
+            tracing::error!(%err, "failed to flush the audit log");
+        }
+    }
+
+    fn append(&mut self, entry: &Entry) -> io::Result<()> {
+        serde_json::to_writer(&mut self.audit_log, entry)?;
+        self.audit_log.write_all(b"\n")
+    }
+
+    fn checkpoint(
+        &mut self,
+        inputs: usize,
+        line: u64,
+        reports: ReportLengths,
+        clients: Vec<ClientSnapshot>,
+    ) -> io::Result<()> {
+        self.audit_log.flush()?;
+        self.audit_log.get_ref().sync_data()?;
+        let audit_log_len = self.audit_log.get_ref().metadata()?.len();
+        let checkpoint = Checkpoint::new(inputs, line, audit_log_len, reports, clients);
+        write_atomically(&self.dir.join(CHECKPOINT_FILE), &checkpoint)
+    }
+}
@@ -0,0 +1,190 @@
+//! Rejections describe the input records which were not applied, and why.
+//!
+//! They are optionally collected by the stream processor and the shards and written
+//! to a separate CSV file, so that `stdout` only contains the final client states.
+
+use std::sync::Arc;
+
+use futures_util::io::AsyncWrite;
+use serde::Serialize;
+
+use crate::{
+    ReadError, balances, csv,
+    csv::Kind,
+    error,
+    report::{self, CsvWriter},
+};
+
+const REJECTION_CHANNEL_SIZE: usize = 10_000;
+
+/// Machine-readable reason of a rejection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[non_exhaustive]
+#[serde(rename_all = "snake_case")]
+pub enum Reason {
+    MalformedRecord,
+    MissingAmount,
+    MissingCurrency,
+    UnexpectedCurrency,
+    MissingAuthorisation,
+    MissingDestination,
+    TransferToSameClient,
+    NonPositiveAmount,
+    TooManyDecimalPlaces,
+    AmountOutOfRange,
+    InsufficientFunds,
+    ArithmeticOverflow,
+    DuplicateTransaction,
+    UnknownTransaction,
+    AlreadyDisputed,
+    NotDisputed,
+    PrunedTransaction,
+    AccountLocked,
+    AccountNotLocked,
+    StorageFailure,
+    ProcessingFailure,
+}
+
+impl From<&ReadError> for Reason {
+    fn from(_: &ReadError) -> Self {
+        Self::MalformedRecord
+    }
+}
+
+impl From<&csv::Error> for Reason {
+    fn from(err: &csv::Error) -> Self {
+        match err {
+            csv::Error::DepositMustHaveAmount
+            | csv::Error::WithdrawalMustHaveAmount
+            | csv::Error::TransferMustHaveAmount => Self::MissingAmount,
+            csv::Error::DepositMustHaveNonZeroAmount
+            | csv::Error::WithdrawalMustHaveNonZeroAmount
+            | csv::Error::TransferMustHaveNonZeroAmount => Self::NonPositiveAmount,
+            csv::Error::AmountHasTooManyDecimalPlaces => Self::TooManyDecimalPlaces,
+            csv::Error::AmountOutOfRange => Self::AmountOutOfRange,
+            csv::Error::InvalidAmount => Self::MalformedRecord,
+            csv::Error::AmountMustHaveCurrency => Self::MissingCurrency,
+            csv::Error::AmountMustNotHaveCurrency => Self::UnexpectedCurrency,
+            csv::Error::TransferMustHaveDestination => Self::MissingDestination,
+            csv::Error::TransferToSameClient => Self::TransferToSameClient,
+            csv::Error::UnlockMustBeAuthorised => Self::MissingAuthorisation,
+        }
+    }
+}
+
+impl From<&balances::Error> for Reason {
+    fn from(err: &balances::Error) -> Self {
+        match err {
+            balances::Error::ArithmeticOverflow => Self::ArithmeticOverflow,
+            balances::Error::InsufficientFunds => Self::InsufficientFunds,
+        }
+    }
+}
+
+impl From<&error::Error> for Reason {
+    fn from(err: &error::Error) -> Self {
+        match err {
+            error::Error::DuplicatedTransaction { .. } => Self::DuplicateTransaction,
+            error::Error::UnknownTransaction { .. } => Self::UnknownTransaction,
+            error::Error::AlreadyDisputed { .. } => Self::AlreadyDisputed,
+            error::Error::NotDisputed { .. } => Self::NotDisputed,
+            error::Error::PrunedTransaction { .. } => Self::PrunedTransaction,
+            error::Error::AccountLocked { .. } => Self::AccountLocked,
+            error::Error::AccountNotLocked { .. } => Self::AccountNotLocked,
+            error::Error::Balances(err) => err.into(),
+            error::Error::Database(_) => Self::StorageFailure,
+            error::Error::ShardUnavailable { .. } => Self::ProcessingFailure,
+        }
+    }
+}
+
+/// A single rejected input record. Fields which could not be read from
+/// a malformed record are left empty.
+#[derive(Debug, Clone, Serialize)]
+pub struct Rejection {
+    // Empty if the processor was not told which input the record came from.
+    file: Option<Arc<str>>,
+    line: Option<u64>,
+    #[serde(rename = "type")]
+    kind: Option<Kind>,
+    client: Option<u16>,
+    tx: Option<u32>,
+    reason: Reason,
+}
+
+impl Rejection {
+    pub(crate) fn new(
+        file: Option<Arc<str>>,
+        line: u64,
+        kind: Kind,
+        client: u16,
+        tx: u32,
+        reason: Reason,
+    ) -> Self {
+        Self {
+            file,
+            line: Some(line),
+            kind: Some(kind),
+            client: Some(client),
+            tx: Some(tx),
+            reason,
+        }
+    }
+
+    pub(crate) fn malformed(file: Option<Arc<str>>, err: &ReadError) -> Self {
+        Self {
+            file,
+            line: err.line(),
+            kind: None,
+            client: None,
+            tx: None,
+            reason: err.into(),
+        }
+    }
+    /// Name of the input the record was read from.
+    pub fn file(&self) -> Option<&str> {
+        self.file.as_deref()
+    }
+
+    /// Line of the input, if the record could be read far enough to know it.
+    pub fn line(&self) -> Option<u64> {
+        self.line
+    }
+
+    pub fn kind(&self) -> Option<Kind> {
+        self.kind
+    }
+
+    pub fn client(&self) -> Option<u16> {
+        self.client
+    }
+
+    pub fn tx(&self) -> Option<u32> {
+        self.tx
+    }
+
+    pub fn reason(&self) -> Reason {
+        self.reason
+    }
+}
+
+pub fn channel() -> (report::Sender<Rejection>, report::Receiver<Rejection>) {
+    report::channel(REJECTION_CHANNEL_SIZE)
+}
+
+/// Writes the rejections as CSV until all senders are dropped, after the `len` bytes
+/// already in the file. The header is only written to an empty file.
+pub async fn write_csv<W>(
+    mut receiver: report::Receiver<Rejection>,
+    writer: W,
+    len: u64,
+) -> Result<(), csv_async::Error>
+where
+    W: AsyncWrite + Unpin,
+{
+    let mut writer = CsvWriter::new(writer, len);
+    while let Some(rejection) = writer.next(&mut receiver).await? {
+        writer.serialize(&rejection).await?;
+    }
+    writer.finish().await
+}
@@ -0,0 +1,169 @@
+//! Channels of the reports written alongside the processing, i.e. the rejections and the
+//! outcomes.
+//!
+//! Besides the rows, a channel carries marks. The writer answers a mark once all rows sent
+//! before it are flushed, with the length of the report at that point. This is how a
+//! checkpoint learns which part of the reports belongs to the records before it.
+
+use std::{
+    io,
+    pin::Pin,
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+    },
+    task::{Context, Poll},
+};
+
+use csv_async::{AsyncSerializer, AsyncWriterBuilder};
+use futures_util::io::AsyncWrite;
+use serde::Serialize;
+use tokio::sync::{mpsc, oneshot};
+
+#[derive(Debug)]
+enum Item<T> {
+    Row(T),
+    Mark(oneshot::Sender<u64>),
+}
+
+/// Sending side of a report, handed to the stream processor.
+#[derive(Debug)]
+pub struct Sender<T>(mpsc::Sender<Item<T>>);
+
+// Not derived, the rows need not be `Clone`.
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        Self(self.0.clone())
+    }
+}
+
+impl<T> Sender<T> {
+    pub(crate) async fn send(&self, row: T) -> io::Result<()> {
+        self.0.send(Item::Row(row)).await.map_err(|_| stopped())
+    }
+
+    /// Waits until the rows sent so far are flushed, and returns the length of the report.
+    pub(crate) async fn mark(&self) -> io::Result<u64> {
+        let (reply, len) = oneshot::channel();
+        self.0
+            .send(Item::Mark(reply))
+            .await
+            .map_err(|_| stopped())?;
+        len.await.map_err(|_| stopped())
+    }
+}
+
+/// Receiving side of a report, handed to its writer.
+#[derive(Debug)]
+pub struct Receiver<T>(mpsc::Receiver<Item<T>>);
+
+pub(crate) fn channel<T>(size: usize) -> (Sender<T>, Receiver<T>) {
+    let (sender, receiver) = mpsc::channel(size);
+    (Sender(sender), Receiver(receiver))
+}
+
+fn stopped() -> io::Error {
+    io::Error::other("the report writer has stopped")
+}
+
+// Writes the rows as CSV after the `len` bytes already in the report, and answers the marks.
+// The header is only written to an empty report.
+pub(crate) struct CsvWriter<W: AsyncWrite + Unpin> {
+    serializer: AsyncSerializer<Counting<W>>,
+    len: Arc<AtomicU64>,
+}
+
+impl<W: AsyncWrite + Unpin> CsvWriter<W> {
+    pub(crate) fn new(writer: W, len: u64) -> Self {
+        let len = Arc::new(AtomicU64::new(len));
+        let serializer = AsyncWriterBuilder::new()
+            .has_headers(len.load(Ordering::Relaxed) == 0)
+            .create_serializer(Counting {
+                inner: writer,
+                len: len.clone(),
+            });
+        Self { serializer, len }
+    }
+
+    /// Next row, `None` once all senders are dropped.
+    pub(crate) async fn next<T>(
+        &mut self,
+        receiver: &mut Receiver<T>,
+    ) -> Result<Option<T>, csv_async::Error> {
+        while let Some(item) = receiver.0.recv().await {
+            match item {
+                Item::Row(row) => return Ok(Some(row)),
+                Item::Mark(reply) => {
+                    self.serializer.flush().await?;
+                    // The checkpoint may have been given up meanwhile.
+                    let _ = reply.send(self.len.load(Ordering::Relaxed));
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    pub(crate) async fn serialize<S: Serialize>(&mut self, row: S) -> Result<(), csv_async::Error> {
+        self.serializer.serialize(row).await
+    }
+
+    pub(crate) async fn finish(mut self) -> Result<(), csv_async::Error> {
+        self.serializer.flush().await?;
+        Ok(())
+    }
+}
+
+// Counts the bytes written through it.
+struct Counting<W> {
+    inner: W,
+    len: Arc<AtomicU64>,
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for Counting<W> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let written = Pin::new(&mut self.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(n)) = written {
+            self.len.fetch_add(n as u64, Ordering::Relaxed);
+        }
+        written
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_close(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{CsvWriter, channel};
+
+    #[tokio::test]
+    async fn marks_answer_with_the_flushed_length() {
+        let (sender, mut receiver) = channel::<u32>(10);
+        let writer = tokio::spawn(async move {
+            let mut buffer = Vec::new();
+            let mut writer = CsvWriter::new(&mut buffer, 5);
+            while let Some(row) = writer.next(&mut receiver).await.unwrap() {
+                writer.serialize([row]).await.unwrap();
+            }
+            writer.finish().await.unwrap();
+            buffer
+        });
+        sender.send(1).await.unwrap();
+        sender.send(22).await.unwrap();
+        assert_eq!(sender.mark().await.unwrap(), 5 + 5);
+        sender.send(3).await.unwrap();
+        assert_eq!(sender.mark().await.unwrap(), 5 + 7);
+        drop(sender);
+        // No header after the earlier bytes.
+        assert_eq!(writer.await.unwrap(), b"1\n22\n3\n");
+    }
+}
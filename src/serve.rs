@@ -0,0 +1,255 @@
+//! Long-running mode, in which the transactions arrive over TCP connections.
+//!
+//! Every connection carries a stream of records in the input format, e.g. a CSV with
+//! a header. The records of all connections are applied to a single `StreamProcessor`
+//! in the order they arrive, so the client processors live across connections.
+
+use std::{future::Future, io, net::SocketAddr, sync::Arc};
+
+use csv_async::AsyncReaderBuilder;
+use futures_util::{Stream, StreamExt};
+use tokio::{
+    net::{TcpListener, TcpStream},
+    sync::mpsc,
+    task::JoinSet,
+};
+use tokio_util::compat::TokioAsyncReadCompatExt;
+use tx_processor::{InputAmount, ReadError, StreamProcessor, csv, jsonl};
+
+use crate::{cli::InputFormat, http};
+
+// Records read ahead of the processor, shared by all connections.
+const RECORD_CHANNEL_SIZE: usize = 10_000;
+
+type Record = Result<csv::InputRecord<InputAmount>, ReadError>;
+
+/// Accepts connections until `shutdown` completes. Records of the connections still open
+/// at that point are applied only as far as they were read. Meanwhile, the `queries` for
+/// the current client states are answered.
+///
+/// All connections must agree with `currency_column` on whether the amounts have
+/// currencies.
+pub(super) async fn serve<F>(
+    listener: TcpListener,
+    format: InputFormat,
+    stream_processor: &mut StreamProcessor<InputAmount>,
+    currency_column: &csv::CurrencyColumn,
+    mut queries: mpsc::Receiver<http::Query>,
+    shutdown: F,
+) -> io::Result<()>
+where
+    F: Future<Output = ()>,
+{
+    let (sender, mut receiver) = mpsc::channel(RECORD_CHANNEL_SIZE);
+    let mut connections = JoinSet::new();
+    tokio::pin!(shutdown);
+    loop {
+        tokio::select! {
+            accepted = listener.accept() => {
+                let (socket, peer) = match accepted {
+                    Ok(accepted) => accepted,
+                    // E.g. too many open files, the listener itself is still fine.
+                    Err(err) => {
+                        tracing::error!(%err, "could not accept connection");
+                        continue;
+                    }
+                };
+                tracing::info!(%peer, "connection accepted");
+                let (sender, currency_column) = (sender.clone(), currency_column.clone());
+                connections.spawn(async move {
+                    if let Err(err) = read(socket, peer, format, sender, currency_column).await {
+                        tracing::error!(%err, %peer, "connection closed");
+                    }
+                });
+            }
+            Some((source, record)) = receiver.recv() => {
+                stream_processor.feed_record(Some(source), record).await;
+            }
+            Some(query) = queries.recv() => {
+                // Answered by the shard, so that the records keep flowing meanwhile.
+                let state = stream_processor.client_state(query.client);
+                tokio::spawn(async move {
+                    if query.reply.send(state.await).is_err() {
+                        tracing::debug!("client state no longer awaited");
+                    }
+                });
+            }
+            // Finished connections are reaped, so that the set does not grow forever.
+            Some(_) = connections.join_next() => {}
+            () = &mut shutdown => break,
+        }
+    }
+
+    // Apply what has been read so far. The channel is closed once all connections are gone.
+    connections.shutdown().await;
+    drop(sender);
+    while let Some((source, record)) = receiver.recv().await {
+        stream_processor.feed_record(Some(source), record).await;
+    }
+    Ok(())
+}
+
+// Reads the records of a single connection until it is closed.
+async fn read(
+    socket: TcpStream,
+    peer: SocketAddr,
+    format: InputFormat,
+    sender: mpsc::Sender<(Arc<str>, Record)>,
+    currency_column: csv::CurrencyColumn,
+) -> io::Result<()> {
+    let source: Arc<str> = peer.to_string().into();
+    match format {
+        InputFormat::Jsonl => {
+            // Checked record by record, since there is no header.
+            let records = jsonl::records_with(
+                futures_util::io::BufReader::new(socket.compat()),
+                currency_column,
+            );
+            forward(source, records, &sender).await;
+        }
+        InputFormat::Csv => {
+            let mut csv_reader = AsyncReaderBuilder::new()
+                .has_headers(true)
+                .trim(csv_async::Trim::All)
+                .create_deserializer(socket.compat());
+            let has_currency = csv::has_currency_column(&mut csv_reader)
+                .await
+                .map_err(io::Error::other)?;
+            if !currency_column.agrees(has_currency) {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "currency column does not match the previous connections",
+                ));
+            }
+            let records = csv::records::<_, InputAmount>(&mut csv_reader);
+            forward(source, records, &sender).await;
+        }
+    }
+    Ok(())
+}
+
+async fn forward<S>(source: Arc<str>, mut records: S, sender: &mpsc::Sender<(Arc<str>, Record)>)
+where
+    S: Stream<Item = Record> + Unpin,
+{
+    while let Some(record) = records.next().await {
+        if sender.send((source.clone(), record)).await.is_err() {
+            // The server is shutting down.
+            break;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        sync::oneshot,
+    };
+
+    use super::*;
+
+    // Sends `input` over a new connection and waits until the server has read all of it.
+    async fn send(addr: SocketAddr, input: &str) {
+        let mut socket = TcpStream::connect(addr).await.unwrap();
+        socket.write_all(input.as_bytes()).await.unwrap();
+        socket.shutdown().await.unwrap();
+        // The server closes its side only after the last record is read. A refused connection
+        // may be reset instead.
+        let _ = socket.read_to_end(&mut Vec::new()).await;
+    }
+
+    async fn balances(input_format: InputFormat, inputs: &[&str]) -> Vec<(u16, String)> {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (stop, stopped) = oneshot::channel::<()>();
+        let inputs: Vec<_> = inputs.iter().map(|input| input.to_string()).collect();
+        let clients = tokio::spawn(async move {
+            for input in inputs {
+                send(addr, &input).await;
+            }
+            stop.send(()).unwrap();
+        });
+
+        let mut stream_processor = StreamProcessor::new();
+        let (_queries, query_receiver) = http::channel();
+        serve(
+            listener,
+            input_format,
+            &mut stream_processor,
+            &csv::CurrencyColumn::default(),
+            query_receiver,
+            async {
+                stopped.await.unwrap();
+            },
+        )
+        .await
+        .unwrap();
+        clients.await.unwrap();
+        stream_processor
+            .finish()
+            .await
+            .map(|state| {
+                let state = state.unwrap();
+                let (_, balances) = state.balances().next().unwrap();
+                (state.client(), balances.available().fixed(2).to_string())
+            })
+            .collect()
+            .await
+    }
+
+    #[tokio::test]
+    async fn clients_live_across_connections() {
+        let balances = balances(
+            InputFormat::Csv,
+            &[
+                "type,client,tx,amount\ndeposit,1,1,5.0\ndeposit,2,2,1.0\n",
+                "type,client,tx,amount\nwithdrawal,1,3,2.0\n",
+            ],
+        )
+        .await;
+        assert_eq!(
+            balances,
+            vec![(1, "3.00".to_string()), (2, "1.00".to_string())]
+        );
+    }
+
+    #[tokio::test]
+    async fn json_lines() {
+        let balances = balances(
+            InputFormat::Jsonl,
+            &[
+                "{\"type\":\"deposit\",\"client\":1,\"tx\":1,\"amount\":\"5.0\"}\n",
+                "{\"type\":\"withdrawal\",\"client\":1,\"tx\":2,\"amount\":1.5}\n",
+            ],
+        )
+        .await;
+        assert_eq!(balances, vec![(1, "3.50".to_string())]);
+    }
+
+    #[tokio::test]
+    async fn mismatching_currency_column_is_refused() {
+        let balances = balances(
+            InputFormat::Csv,
+            &[
+                "type,client,tx,amount\ndeposit,1,1,5.0\n",
+                "type,client,tx,amount,currency\ndeposit,1,2,1.0,EUR\n",
+            ],
+        )
+        .await;
+        assert_eq!(balances, vec![(1, "5.00".to_string())]);
+    }
+
+    #[tokio::test]
+    async fn mismatching_json_currency_is_rejected() {
+        let balances = balances(
+            InputFormat::Jsonl,
+            &[
+                "{\"type\":\"deposit\",\"client\":1,\"tx\":1,\"amount\":5,\"currency\":\"EUR\"}\n",
+                "{\"type\":\"deposit\",\"client\":1,\"tx\":2,\"amount\":1}\n",
+            ],
+        )
+        .await;
+        assert_eq!(balances, vec![(1, "5.00".to_string())]);
+    }
+}
@@ -0,0 +1,238 @@
+//! A shard is a worker task which owns the client processors of a subset of clients.
+//!
+//! Transactions of a single client are always sent to the same shard and are processed
+//! one by one, so their order is preserved.
+
+use std::{collections::HashMap, sync::Arc};
+
+use tokio::sync::{mpsc, oneshot};
+
+use crate::{
+    client_processor::{ClientProcessor, ClientState, WithdrawalDisputePolicy},
+    csv::Kind,
+    db::{Backend, Cache},
+    error::Error,
+    outcome::Outcome,
+    recovery::{Entry, RecoveryDir},
+    rejection::{Reason, Rejection},
+    report,
+    snapshot::ClientSnapshot,
+    transaction::Transaction,
+};
+
+/// A transaction together with the input and the line it was read from.
+pub(super) struct Job {
+    source: Option<Arc<str>>,
+    line: u64,
+    tx: Transaction,
+    // If set, the outcome is sent back instead of being reported as a rejection.
+    reply: Option<oneshot::Sender<Result<(), Error>>>,
+}
+
+impl Job {
+    pub(super) fn new(source: Option<Arc<str>>, line: u64, tx: Transaction) -> Self {
+        Self {
+            source,
+            line,
+            tx,
+            reply: None,
+        }
+    }
+
+    /// Creates a job whose outcome is awaited by the sender.
+    pub(super) fn with_reply(
+        source: Option<Arc<str>>,
+        line: u64,
+        tx: Transaction,
+    ) -> (Self, oneshot::Receiver<Result<(), Error>>) {
+        let (sender, receiver) = oneshot::channel();
+        let job = Self {
+            source,
+            line,
+            tx,
+            reply: Some(sender),
+        };
+        (job, receiver)
+    }
+}
+
+/// Requests handled by a shard, in the order they are sent.
+pub(super) enum Request {
+    Apply(Job),
+    /// Current state of a client, `None` if the shard has not seen it yet. It reflects all
+    /// transactions sent to the shard before the request.
+    State {
+        client: u16,
+        reply: oneshot::Sender<Option<ClientState>>,
+    },
+    /// Snapshots of all clients, reflecting all transactions sent before the request.
+    /// Clients whose deposits are kept on disk are left out.
+    Snapshot {
+        reply: oneshot::Sender<Vec<ClientSnapshot>>,
+    },
+}
+
+pub(super) struct Shard {
+    // Client processors are created when the first transaction of a client arrives.
+    clients: HashMap<u16, ClientProcessor<Cache>>,
+    // Storage for the deposit caches of the newly created client processors.
+    backend: Backend,
+    // Passed to the newly created client processors.
+    withdrawal_dispute_policy: WithdrawalDisputePolicy,
+    // The channel to receive requests from the stream processor.
+    tx_receiver: mpsc::Receiver<Request>,
+    // The channel to send the results back to the stream processor.
+    result_sender: Option<oneshot::Sender<Vec<ClientState>>>,
+    // The channel to report transactions which could not be applied.
+    rejections: Option<report::Sender<Rejection>>,
+    // The channel to report the outcome of every transaction.
+    outcomes: Option<report::Sender<Outcome>>,
+    // Every applied transaction is appended to its audit log, if set.
+    recovery: Option<RecoveryDir>,
+}
+
+impl Shard {
+    pub(super) fn new(
+        backend: Backend,
+        withdrawal_dispute_policy: WithdrawalDisputePolicy,
+        tx_receiver: mpsc::Receiver<Request>,
+        result_sender: oneshot::Sender<Vec<ClientState>>,
+        rejections: Option<report::Sender<Rejection>>,
+        outcomes: Option<report::Sender<Outcome>>,
+        recovery: Option<RecoveryDir>,
+    ) -> Self {
+        Self {
+            clients: HashMap::new(),
+            backend,
+            withdrawal_dispute_policy,
+            tx_receiver,
+            result_sender: Some(result_sender),
+            rejections,
+            outcomes,
+            recovery,
+        }
+    }
+
+    /// Adds clients restored from a snapshot.
+    pub(super) fn with_clients<I>(mut self, clients: I) -> Self
+    where
+        I: IntoIterator<Item = ClientProcessor<Cache>>,
+    {
+        self.clients
+            .extend(clients.into_iter().map(|client| (client.client(), client)));
+        self
+    }
+
+    /// Processes requests until the channel is closed, then sends the final client states.
+    pub(super) async fn crank(&mut self) {
+        while let Some(request) = self.tx_receiver.recv().await {
+            match request {
+                Request::Apply(job) => self.apply(job).await,
+                Request::State { client, reply } => {
+                    let state = self.clients.get(&client).map(ClientProcessor::state);
+                    if reply.send(state).is_err() {
+                        tracing::debug!(client, "client state no longer awaited");
+                    }
+                }
+                Request::Snapshot { reply } => {
+                    let snapshots = self
+                        .clients
+                        .values()
+                        .filter_map(ClientProcessor::snapshot)
+                        .collect();
+                    if reply.send(snapshots).is_err() {
+                        tracing::error!("failed to send snapshots of the shard");
+                    }
+                }
+            }
+        }
+
+        if let Some(sender) = self.result_sender.take() {
+            let states = self.clients.values().map(ClientProcessor::state).collect();
+            if sender.send(states).is_err() {
+                tracing::error!("failed to send results of the shard");
+            }
+        }
+    }
+
+    // Applies the transaction and reports its outcome.
+    async fn apply(&mut self, job: Job) {
+        let Job {
+            source,
+            line,
+            tx,
+            reply,
+        } = job;
+        let (kind, client, id) = (Kind::from(&tx), tx.client(), tx.tx());
+        let client_processor = self.clients.entry(client).or_insert_with(|| {
+            ClientProcessor::new(
+                client,
+                self.backend.cache(client),
+                self.withdrawal_dispute_policy,
+            )
+        });
+        // Built before the transaction is consumed, written only if it is applied.
+        let entry = self
+            .recovery
+            .as_ref()
+            .map(|_| Entry::new(source.clone(), line, &tx));
+        // Looked up before the transaction, e.g. a chargeback forgets the disputed amount.
+        let currency = self
+            .outcomes
+            .as_ref()
+            .and_then(|_| client_processor.currency_of(&tx));
+        let authorised_by = match (&self.outcomes, &tx) {
+            (Some(_), Transaction::Unlock(unlock)) => Some(unlock.authorised_by().to_owned()),
+            _ => None,
+        };
+        let span = tracing::info_span!(
+            parent: client_processor.span(),
+            "tx",
+            line,
+            tx = id,
+            ?kind
+        );
+        let result = span.in_scope(|| {
+            let result = client_processor.handle(tx);
+            match &result {
+                Ok(()) => tracing::debug!("transaction applied"),
+                Err(err) => tracing::warn!(%err, "transaction rejected"),
+            }
+            result
+        });
+        if let (Some(recovery), Some(entry), Ok(())) = (&self.recovery, entry, &result) {
+            if let Err(err) = recovery.append(entry).await {
+                tracing::error!(%err, "failed to append to the audit log");
+            }
+        }
+        if let Some(outcomes) = &self.outcomes {
+            let outcome = Outcome::new(
+                source.clone(),
+                line,
+                kind,
+                client,
+                id,
+                result.as_ref().err().map(Reason::from),
+                currency,
+                client_processor.balances(currency),
+                client_processor.locked(),
+            )
+            .with_authorised_by(authorised_by);
+            if let Err(err) = outcomes.send(outcome).await {
+                tracing::error!(%err, "failed to report outcome");
+            }
+        }
+        if let Some(reply) = reply {
+            if reply.send(result).is_err() {
+                tracing::error!("failed to reply with the outcome of the transaction");
+            }
+        } else if let Err(err) = result {
+            if let Some(rejections) = &self.rejections {
+                let rejection = Rejection::new(source, line, kind, client, id, (&err).into());
+                if let Err(err) = rejections.send(rejection).await {
+                    tracing::error!(%err, "failed to report rejection");
+                }
+            }
+        }
+    }
+}
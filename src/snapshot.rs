@@ -0,0 +1,325 @@
+//! Serializable state of the client processors, for the checkpoints and the snapshots.
+//!
+//! A `Snapshot` holds the state of all clients at the end of a run, so that the next run
+//! can start from it instead of replaying the whole history, e.g. yesterday's closing
+//! state is the opening state for today's file.
+//!
+//! Amounts are decimal strings, so that the files stay readable and do not depend on the
+//! fixed-point representation. Cached transactions keep their age rather than the instant
+//! they were cached at, which means nothing to another process.
+
+use std::{
+    fs::{self, File},
+    io::{self, BufReader, BufWriter, Write},
+    path::Path,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    NonNegative, NonZero,
+    balances::{BalanceUpdater, Balances},
+    csv::CurrencyColumn,
+    currency::Currency,
+    db::Disputable,
+};
+
+// Snapshots with another version are refused rather than misread.
+const SNAPSHOT_VERSION: u32 = 1;
+
+/// State of all clients: their balances, whether they are locked, the transactions under
+/// dispute and the deposit cache. Only the in-memory deposit cache can be captured.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Snapshot {
+    version: u32,
+    clients: Vec<ClientSnapshot>,
+}
+
+impl Snapshot {
+    pub(crate) fn new(clients: Vec<ClientSnapshot>) -> Self {
+        Self {
+            version: SNAPSHOT_VERSION,
+            clients,
+        }
+    }
+
+    /// Reads a snapshot written by `write`.
+    pub fn read<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let file = BufReader::new(File::open(path)?);
+        let snapshot: Self = serde_json::from_reader(file)?;
+        if snapshot.version != SNAPSHOT_VERSION {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unsupported snapshot version {}", snapshot.version),
+            ));
+        }
+        validate(&snapshot.clients)?;
+        Ok(snapshot)
+    }
+
+    /// Writes the snapshot as JSON. An existing file is only replaced once the snapshot is
+    /// complete.
+    pub fn write<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        write_atomically(path.as_ref(), self)
+    }
+
+    /// Number of clients in the snapshot.
+    pub fn client_count(&self) -> usize {
+        self.clients.len()
+    }
+
+    /// Currency column decided by the balances, which the inputs continuing from the
+    /// snapshot must agree with. Undecided without any balances.
+    pub fn currency_column(&self) -> CurrencyColumn {
+        let column = CurrencyColumn::default();
+        let mut balances = self.clients.iter().flat_map(|client| &client.balances);
+        if let Some(balances) = balances.next() {
+            column.agrees(balances.currency.is_some());
+        }
+        column
+    }
+
+    pub(crate) fn into_clients(self) -> Vec<ClientSnapshot> {
+        self.clients
+    }
+}
+
+// Refuses balances which the processing would never produce, since their total could not
+// be reported, or they could not share the columns of the output.
+pub(crate) fn validate(clients: &[ClientSnapshot]) -> io::Result<()> {
+    let currency_column = CurrencyColumn::default();
+    for client in clients {
+        for balances in &client.balances {
+            if balances.available.add(balances.held).is_none() {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("total balance of client {} overflows", client.client),
+                ));
+            }
+            if !currency_column.agrees(balances.currency.is_some()) {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "balances of client {} do not match the currency column of the others",
+                        client.client
+                    ),
+                ));
+            }
+        }
+    }
+    Ok(())
+}
+
+// Writes the value as JSON to a temporary file which then replaces `path` in a single step,
+// so that a crash leaves either the old or the new file.
+pub(crate) fn write_atomically<T: Serialize>(path: &Path, value: &T) -> io::Result<()> {
+    let mut temporary = path.as_os_str().to_owned();
+    temporary.push(".tmp");
+    let mut file = BufWriter::new(File::create(&temporary)?);
+    serde_json::to_writer(&mut file, value)?;
+    file.flush()?;
+    file.get_ref().sync_all()?;
+    fs::rename(&temporary, path)
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct ClientSnapshot {
+    pub(crate) client: u16,
+    pub(crate) locked: bool,
+    pub(crate) balances: Vec<BalancesSnapshot>,
+    // Transactions under dispute.
+    pub(crate) disputed: Vec<CachedSnapshot>,
+    // Transactions which can be disputed, oldest first if there is a pruning strategy.
+    pub(crate) cached: Vec<CachedSnapshot>,
+    // IDs of the transactions evicted from the cache by the pruning strategy, oldest first.
+    pub(crate) pruned: Vec<PrunedSnapshot>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct BalancesSnapshot {
+    currency: Option<Currency>,
+    #[serde(with = "amount")]
+    available: NonNegative,
+    #[serde(with = "amount")]
+    held: NonNegative,
+}
+
+impl BalancesSnapshot {
+    pub(crate) fn new(currency: Option<Currency>, balances: &Balances) -> Self {
+        Self {
+            currency,
+            available: balances.available(),
+            held: balances.held(),
+        }
+    }
+
+    pub(crate) fn restore(&self) -> (Option<Currency>, Balances) {
+        (
+            self.currency,
+            Balances::new_with_values(self.available, self.held),
+        )
+    }
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum Kind {
+    Deposit,
+    Withdrawal,
+    Transfer,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct CachedSnapshot {
+    pub(crate) tx: u32,
+    #[serde(rename = "type")]
+    kind: Kind,
+    #[serde(with = "amount")]
+    amount: NonZero,
+    currency: Option<Currency>,
+    // Milliseconds since the transaction was cached, only tracked with the `Ttl` strategy.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) age_ms: Option<u64>,
+}
+
+impl CachedSnapshot {
+    pub(crate) fn new(tx: u32, value: Disputable, age_ms: Option<u64>) -> Self {
+        let (kind, amount, currency) = match value {
+            Disputable::Deposit { amount, currency } => (Kind::Deposit, amount, currency),
+            Disputable::Withdrawal { amount, currency } => (Kind::Withdrawal, amount, currency),
+            Disputable::Transfer { amount, currency } => (Kind::Transfer, amount, currency),
+        };
+        Self {
+            tx,
+            kind,
+            amount,
+            currency,
+            age_ms,
+        }
+    }
+
+    pub(crate) fn value(&self) -> Disputable {
+        let (amount, currency) = (self.amount, self.currency);
+        match self.kind {
+            Kind::Deposit => Disputable::Deposit { amount, currency },
+            Kind::Withdrawal => Disputable::Withdrawal { amount, currency },
+            Kind::Transfer => Disputable::Transfer { amount, currency },
+        }
+    }
+}
+
+// A transaction evicted from the cache, remembered only so that its ID is not reused.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct PrunedSnapshot {
+    pub(crate) tx: u32,
+    // Milliseconds since the transaction was cached.
+    pub(crate) age_ms: u64,
+}
+
+impl PrunedSnapshot {
+    pub(crate) fn new(tx: u32, age_ms: u64) -> Self {
+        Self { tx, age_ms }
+    }
+}
+
+// Amounts as decimal strings. Values out of the range of the type are rejected, so that
+// a tampered file can not break the invariants of the balances.
+mod amount {
+    use serde::{Deserialize, Deserializer, Serializer, de::Error};
+
+    use crate::Amount;
+
+    pub(super) fn serialize<T, S>(value: &T, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        T: Copy + Into<Amount>,
+        S: Serializer,
+    {
+        serializer.collect_str(&(*value).into())
+    }
+
+    pub(super) fn deserialize<'de, T, D>(deserializer: D) -> Result<T, D::Error>
+    where
+        T: TryFrom<Amount>,
+        D: Deserializer<'de>,
+    {
+        let s = String::deserialize(deserializer)?;
+        let amount: Amount = s.parse().map_err(D::Error::custom)?;
+        T::try_from(amount).map_err(|_| D::Error::custom(format!("amount out of range: {s}")))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Amount;
+
+    #[test]
+    fn cached_roundtrip() {
+        let value = Disputable::Withdrawal {
+            amount: Amount::from(5).try_into().unwrap(),
+            currency: Some("EUR".parse().unwrap()),
+        };
+        let json = serde_json::to_string(&CachedSnapshot::new(7, value, None)).unwrap();
+        assert_eq!(
+            json,
+            r#"{"tx":7,"type":"withdrawal","amount":"5","currency":"EUR"}"#
+        );
+        let snapshot: CachedSnapshot = serde_json::from_str(&json).unwrap();
+        assert_eq!(snapshot.value(), value);
+    }
+
+    #[test]
+    fn non_positive_amount_is_rejected() {
+        let json = r#"{"tx":7,"type":"deposit","amount":"0","currency":null}"#;
+        assert!(serde_json::from_str::<CachedSnapshot>(json).is_err());
+    }
+
+    #[test]
+    fn negative_balance_is_rejected() {
+        let json = r#"{"currency":null,"available":"-1","held":"0"}"#;
+        assert!(serde_json::from_str::<BalancesSnapshot>(json).is_err());
+    }
+
+    #[test]
+    fn other_version_is_refused() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("snapshot.json");
+        fs::write(&path, r#"{"version":2,"clients":[]}"#).unwrap();
+        let err = Snapshot::read(&path).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn overflowing_total_is_refused() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("snapshot.json");
+        let balances =
+            r#"{"currency":null,"available":"500000000000000","held":"500000000000000"}"#;
+        let client = format!(
+            r#"{{"client":1,"locked":false,"balances":[{balances}],"disputed":[],"cached":[],"pruned":[]}}"#
+        );
+        fs::write(&path, format!(r#"{{"version":1,"clients":[{client}]}}"#)).unwrap();
+        let err = Snapshot::read(&path).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(err.to_string(), "total balance of client 1 overflows");
+    }
+
+    #[test]
+    fn mixed_currency_column_is_refused() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("snapshot.json");
+        let client = |client: u16, currency: &str| {
+            format!(
+                r#"{{"client":{client},"locked":false,"balances":[{{"currency":{currency},"available":"1","held":"0"}}],"disputed":[],"cached":[],"pruned":[]}}"#
+            )
+        };
+        let clients = [client(1, r#""EUR""#), client(2, "null")].join(",");
+        fs::write(&path, format!(r#"{{"version":1,"clients":[{clients}]}}"#)).unwrap();
+        let err = Snapshot::read(&path).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(
+            err.to_string(),
+            "balances of client 2 do not match the currency column of the others"
+        );
+    }
+}
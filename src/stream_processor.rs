@@ -1,157 +1,594 @@
 //! A stream processor is responsible for processing a stream of CSV transaction.
 //! As a result it produces a stream of final client states.
 
-use std::{
-    collections::HashMap,
-    sync::{
-        Arc,
-        atomic::{AtomicUsize, Ordering},
-    },
-};
+use std::{collections::HashMap, num::NonZeroUsize, sync::Arc};
 
 use futures_util::{Stream, StreamExt, stream};
 use thiserror::Error;
 use tokio::sync::{mpsc, oneshot};
 
 use crate::{
-    ClientProcessor, NonZero, client_processor::ClientState, csv, in_mem, transaction::Transaction,
+    ReadError,
+    amount::{InputAmount, Precision},
+    client_processor::{ClientProcessor, ClientState, WithdrawalDisputePolicy},
+    csv,
+    db::{Backend, Cache},
+    error,
+    outcome::Outcome,
+    recovery::{Checkpoint, RecoveryDir, ReportLengths},
+    rejection::{Reason, Rejection},
+    report,
+    shard::{Job, Request, Shard},
+    snapshot::{ClientSnapshot, Snapshot},
+    transaction::{Credit, Transaction, TransactionPayload, Transfer},
 };
 
 // TODO: This could potentially be a config option to adjust the backpressure
 // for a specific scenario.
 const TX_CHANNEL_SIZE: usize = 10_000;
 
-pub(super) type ClientResult = Result<ClientState, Error>;
+// A shard for every possible client ID.
+const DEFAULT_SHARD_COUNT: usize = u16::MAX as usize + 1;
+
+pub type ClientResult = Result<ClientState, Error>;
 
 #[derive(Debug, Error)]
-pub(super) enum Error {
+#[non_exhaustive]
+pub enum Error {
     #[error(transparent)]
     Csv(#[from] csv_async::Error),
-    #[error(transparent)]
-    Tokio(#[from] tokio::sync::mpsc::error::SendError<Transaction>),
-    #[error("could not receive results for client {client}: {reason}")]
-    CouldNotReceiveResults { client: u16, reason: String },
+    #[error("could not receive results for shard {shard}: {reason}")]
+    CouldNotReceiveResults { shard: usize, reason: String },
+    #[error("snapshots need the deposits to be kept in memory")]
+    SnapshotOnDisk,
 }
 
-// The `Decimal` type, while being convenient for financial calculations,
-// consists of 4 u32 values. This is why `StreamProcessor` abstracts over it
-// so we can build a smaller type and then
-// easily use it with the `StreamProcessor`.
-pub(super) struct StreamProcessor<MonetaryValue>
+// `StreamProcessor` abstracts over the monetary value read from the input. It only has to
+// convert into an `InputAmount`, which keeps the number as written, and is turned into the
+// 8 byte fixed-point `Amount` according to the `Precision` when the record is applied.
+pub struct StreamProcessor<MonetaryValue>
 where
-    MonetaryValue: TryInto<NonZero>,
+    MonetaryValue: Into<InputAmount>,
 {
-    // Each client is handled by a separate processor.
-    // TODO: When there are millions of clients the current approach could be
-    // problematic as we spawn a new task for each client. Potential solutions
+    // Clients are spread over shards by `client % shard_count`. Each shard is
+    // a separate task handling many clients, so the number of tasks and channel
+    // buffers is bounded by the shard count. By default there is a shard for
+    // every possible client ID, i.e. every client gets its own task.
+    // TODO: When there are millions of clients (and only a few shards) the
+    // states of all clients are still kept in memory. Potential solutions
     // to explore if this proves to be a problem:
-    // - Use a batched processing, where there is a limited number of
-    //   processors and each processor handles multiple clients.
-    // - Use LRU cache - keep only N processors alive and reuse them.
+    // - Use LRU cache - keep only N client processors alive in a shard.
     //   Persist a state of the processor when it is not used and
     //   restore when it is needed again.
-    // - Or let the stream processor manage the state of all clients and just
-    //   update it as transactions are processed. This would require locking
-    //   and the state would grow indefinitely anyway.
-    client_processors: HashMap<u16, mpsc::Sender<Transaction>>,
+    shard_count: usize,
+
+    shards: HashMap<usize, mpsc::Sender<Request>>,
+
+    result_receivers: HashMap<usize, oneshot::Receiver<Vec<ClientState>>>,
+
+    // Storage used for the deposit cache of every client.
+    backend: Backend,
+
+    // Decides whether withdrawals can be disputed, and how.
+    withdrawal_dispute_policy: WithdrawalDisputePolicy,
+
+    // Records which could not be applied are reported here, if set.
+    rejections: Option<report::Sender<Rejection>>,
+
+    // The outcome of every transaction handled by the shards is reported here, if set.
+    outcomes: Option<report::Sender<Outcome>>,
+
+    // Decides how many decimal places the input amounts can have.
+    precision: Precision,
+
+    // Name of the input the records are currently read from, reported with the rejections.
+    source: Option<Arc<str>>,
+
+    // The state is checkpointed and the applied transactions are logged here, if set.
+    recovery: Option<RecoveryDir>,
+
+    // Records fed between two checkpoints.
+    checkpoint_interval: usize,
 
-    result_receivers: HashMap<u16, oneshot::Receiver<ClientState>>,
+    // Records fed since the last checkpoint.
+    since_checkpoint: usize,
+
+    // Position of the last fed record: the number of inputs fed completely and the last
+    // line of the current input.
+    inputs_fed: usize,
+    line: u64,
+
+    // Clients of a checkpoint or snapshot to start from, restored before the first record
+    // is fed.
+    restored: Option<Vec<ClientSnapshot>>,
+
+    // Records up to this position were fed before the checkpoint and are skipped.
+    resume_position: Option<(usize, u64)>,
 
     phantom: std::marker::PhantomData<MonetaryValue>,
 }
 
+impl<MonetaryValue> Default for StreamProcessor<MonetaryValue>
+where
+    MonetaryValue: Into<InputAmount>,
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl<MonetaryValue> StreamProcessor<MonetaryValue>
 where
-    MonetaryValue: TryInto<NonZero>,
+    MonetaryValue: Into<InputAmount>,
 {
-    pub(super) fn new() -> Self {
+    pub fn new() -> Self {
         Self {
-            client_processors: HashMap::new(),
+            shard_count: DEFAULT_SHARD_COUNT,
+            shards: HashMap::new(),
             result_receivers: HashMap::new(),
+            backend: Backend::default(),
+            withdrawal_dispute_policy: WithdrawalDisputePolicy::default(),
+            rejections: None,
+            outcomes: None,
+            precision: Precision::default(),
+            source: None,
+            recovery: None,
+            checkpoint_interval: usize::MAX,
+            since_checkpoint: 0,
+            inputs_fed: 0,
+            line: 0,
+            restored: None,
+            resume_position: None,
             phantom: std::marker::PhantomData,
         }
     }
 
-    pub(super) async fn process<S>(&mut self, mut stream: S) -> impl Stream<Item = ClientResult>
+    pub fn with_backend(mut self, backend: Backend) -> Self {
+        self.backend = backend;
+        self
+    }
+
+    pub fn with_withdrawal_dispute_policy(mut self, policy: WithdrawalDisputePolicy) -> Self {
+        self.withdrawal_dispute_policy = policy;
+        self
+    }
+
+    pub fn with_rejections(mut self, rejections: report::Sender<Rejection>) -> Self {
+        self.rejections = Some(rejections);
+        self
+    }
+
+    pub fn with_outcomes(mut self, outcomes: report::Sender<Outcome>) -> Self {
+        self.outcomes = Some(outcomes);
+        self
+    }
+
+    pub fn with_precision(mut self, precision: Precision) -> Self {
+        self.precision = precision;
+        self
+    }
+
+    pub fn with_shard_count(mut self, shard_count: NonZeroUsize) -> Self {
+        self.shard_count = shard_count.get();
+        self
+    }
+
+    /// Checkpoints the state of all clients after every `checkpoint_interval` records, as
+    /// well as when finished, and logs every applied transaction. Only the in-memory
+    /// deposit cache can be checkpointed.
+    pub fn with_recovery(
+        mut self,
+        recovery: RecoveryDir,
+        checkpoint_interval: NonZeroUsize,
+    ) -> Self {
+        self.recovery = Some(recovery);
+        self.checkpoint_interval = checkpoint_interval.get();
+        self
+    }
+
+    /// Continues from the checkpoint. The clients are restored and the records fed before
+    /// the checkpoint are skipped, provided the same inputs are fed in the same order.
+    ///
+    /// Fails if the deposits are kept on disk, so the backend must be chosen before.
+    pub fn with_checkpoint(mut self, checkpoint: Checkpoint) -> Result<Self, Error> {
+        self.ensure_in_memory()?;
+        self.resume_position = Some((checkpoint.inputs(), checkpoint.line()));
+        self.restored = Some(checkpoint.into_clients());
+        Ok(self)
+    }
+
+    /// Starts from the clients of the snapshot, e.g. the closing state of the previous run.
+    /// Unlike a checkpoint, no records are skipped. A checkpoint given later replaces it,
+    /// since it already contains the clients of the snapshot.
+    ///
+    /// Fails if the deposits are kept on disk, so the backend must be chosen before.
+    pub fn with_snapshot(mut self, snapshot: Snapshot) -> Result<Self, Error> {
+        self.ensure_in_memory()?;
+        self.restored = Some(snapshot.into_clients());
+        Ok(self)
+    }
+
+    /// Processes a single input and returns the final client states.
+    pub async fn process<S>(&mut self, stream: S) -> impl Stream<Item = ClientResult>
     where
-        S: Stream<Item = Result<csv::InputRecord<MonetaryValue>, csv_async::Error>> + Unpin,
+        S: Stream<Item = Result<csv::InputRecord<MonetaryValue>, ReadError>> + Unpin,
     {
-        // Use usize so that we can basically ignore potential overflows. If there
-        // are more than usize::MAX transactions in flight, we have bigger problems
-        // already.
-        let active_transactions = Arc::new(AtomicUsize::new(0));
+        self.feed(None, stream).await;
+        self.finish().await
+    }
 
+    /// Applies the records of one of many inputs. Inputs fed one after another form a single
+    /// logical stream, e.g. a dispute can refer to a deposit from an earlier input. The
+    /// `source` names the input in the rejections.
+    pub async fn feed<S>(&mut self, source: Option<Arc<str>>, mut stream: S)
+    where
+        S: Stream<Item = Result<csv::InputRecord<MonetaryValue>, ReadError>> + Unpin,
+    {
+        // Inputs fed completely before the checkpoint are not read at all, the one fed
+        // partially is read past the last fed line.
+        let mut skip_until = None;
+        if let Some((inputs, line)) = self.resume_position {
+            if self.inputs_fed < inputs {
+                self.inputs_fed += 1;
+                return;
+            }
+            self.resume_position = None;
+            skip_until = Some(line);
+        }
         while let Some(record) = stream.next().await {
-            let Ok(record) = record else {
-                //tracing::error!("csv record error");
-                continue;
-            };
+            if let Some(last_fed) = skip_until {
+                let line = match &record {
+                    Ok(record) => Some(record.line()),
+                    Err(err) => err.line(),
+                };
+                if line.is_none_or(|line| line <= last_fed) {
+                    continue;
+                }
+                skip_until = None;
+            }
+            self.feed_record(source.clone(), record).await;
+        }
+        self.inputs_fed += 1;
+        self.line = 0;
+    }
 
-            let Ok(tx): Result<Transaction, _> = record.try_into() else {
-                //tracing::error!("invalid transaction in csv");
-                continue;
-            };
+    /// Applies a single record. Useful when the records of several inputs are interleaved,
+    /// e.g. when they arrive over concurrent connections.
+    pub async fn feed_record(
+        &mut self,
+        source: Option<Arc<str>>,
+        record: Result<csv::InputRecord<MonetaryValue>, ReadError>,
+    ) {
+        self.restore();
+        self.source = source;
+        let line = match &record {
+            Ok(record) => Some(record.line()),
+            Err(err) => err.line(),
+        };
+        if let Some(line) = line {
+            self.line = line;
+        }
+        self.apply_record(record).await;
 
-            let active_transactions = Arc::clone(&active_transactions);
+        self.since_checkpoint += 1;
+        if self.since_checkpoint >= self.checkpoint_interval {
+            self.checkpoint().await;
+        }
+    }
 
-            let client_processor = self.client_processors.get(&tx.client());
-            match client_processor {
-                Some(tx_sender) => {
-                    send_and_register(tx, Arc::clone(&active_transactions), tx_sender).await;
-                }
-                None => {
-                    let (tx_sender, tx_receiver) = mpsc::channel(TX_CHANNEL_SIZE);
-                    let (result_sender, result_receiver) = oneshot::channel();
-                    let client_db = in_mem::AmountCache::new();
-                    let mut client_processor =
-                        ClientProcessor::new(tx.client(), client_db, tx_receiver, result_sender);
-                    self.client_processors
-                        .insert(tx.client(), tx_sender.clone());
-                    self.result_receivers.insert(tx.client(), result_receiver);
-                    tokio::spawn({
-                        let active_transactions = Arc::clone(&active_transactions);
-                        async move {
-                            if let Err(_err) = client_processor
-                                .crank(Arc::clone(&active_transactions))
-                                .await
-                            {
-                                //tracing::error!(%_err);
-                            }
-                        }
-                    });
-                    send_and_register(tx, Arc::clone(&active_transactions), &tx_sender).await;
+    async fn apply_record(&mut self, record: Result<csv::InputRecord<MonetaryValue>, ReadError>) {
+        let record = match record {
+            Ok(record) => record,
+            Err(err) => {
+                tracing::warn!(%err, "malformed record");
+                self.reject(Rejection::malformed(self.source.clone(), &err))
+                    .await;
+                return;
+            }
+        };
+
+        let (line, kind, client, id) = (record.line(), record.kind(), record.client(), record.tx());
+        let tx = match record.into_transaction(self.precision) {
+            Ok(tx) => tx,
+            Err(err) => {
+                tracing::warn!(%err, line, "invalid transaction");
+                let reason = Reason::from(&err);
+                self.reject(Rejection::new(
+                    self.source.clone(),
+                    line,
+                    kind,
+                    client,
+                    id,
+                    reason,
+                ))
+                .await;
+                return;
+            }
+        };
+
+        match tx {
+            Transaction::Transfer(transfer) => {
+                if let Err(err) = self.transfer(line, transfer).await {
+                    tracing::warn!(%err, line, tx = id, "transfer rejected");
+                    let reason = Reason::from(&err);
+                    self.reject(Rejection::new(
+                        self.source.clone(),
+                        line,
+                        kind,
+                        client,
+                        id,
+                        reason,
+                    ))
+                    .await;
                 }
             }
+            tx => {
+                let sender = self.shard_sender(tx.client());
+                send(Job::new(self.source.clone(), line, tx), &sender).await;
+            }
         }
+    }
 
-        // TODO: Busy waiting at the end to make sure all txs are processed.
-        // This could potentially be improved with a more elegant solution.
-        while active_transactions.load(Ordering::SeqCst) > 0 {
-            tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+    /// Waits until all fed records are processed and returns the final client states,
+    /// ordered by the client ID. Errors, if any, come first.
+    pub async fn finish(&mut self) -> impl Stream<Item = ClientResult> {
+        self.restore();
+        if self.recovery.is_some() {
+            self.checkpoint().await;
         }
 
-        // We only drop senders after all transactions are processed.
-        self.client_processors = HashMap::new();
+        // Dropping the senders closes the channels. Each shard sends its
+        // results as soon as it has processed all of the remaining transactions.
+        self.shards = HashMap::new();
 
-        // Read all results from the receivers.
-        stream::iter(self.result_receivers.iter_mut())
-            .then(|(client, receiver)| async move {
-                receiver.await.map_err(|err| Error::CouldNotReceiveResults {
-                    client: *client,
-                    reason: err.to_string(),
-                })
+        // Read all results from the receivers. The shards, and the clients within them,
+        // are kept in hash maps, so the results must be sorted to be reproducible.
+        let mut results: Vec<ClientResult> = stream::iter(self.result_receivers.iter_mut())
+            .then(|(shard, receiver)| async move {
+                match receiver.await {
+                    Ok(states) => states.into_iter().map(Ok).collect(),
+                    Err(err) => vec![Err(Error::CouldNotReceiveResults {
+                        shard: *shard,
+                        reason: err.to_string(),
+                    })],
+                }
             })
-            .boxed()
+            .flat_map(stream::iter)
+            .collect()
+            .await;
+        results.sort_by_key(|result| result.as_ref().ok().map(ClientState::client));
+        stream::iter(results)
+    }
+
+    /// Current state of a client, without waiting for the end of the input. It reflects all
+    /// records fed so far, `None` if none of them was for this client.
+    ///
+    /// The returned future does not borrow the processor, so it can be awaited while more
+    /// records are fed.
+    pub fn client_state(
+        &mut self,
+        client: u16,
+    ) -> impl Future<Output = Result<Option<ClientState>, Error>> + Send + 'static {
+        // The clients of a checkpoint or snapshot exist before any record is fed.
+        self.restore();
+        let shard = usize::from(client) % self.shard_count;
+        let sender = self.shards.get(&shard).cloned();
+        async move {
+            let Some(sender) = sender else {
+                return Ok(None);
+            };
+            let (reply, state) = oneshot::channel();
+            let unavailable = |reason: String| Error::CouldNotReceiveResults { shard, reason };
+            sender
+                .send(Request::State { client, reply })
+                .await
+                .map_err(|err| unavailable(err.to_string()))?;
+            state.await.map_err(|err| unavailable(err.to_string()))
+        }
+    }
+
+    /// Captures the state of all clients once every record fed so far is processed, e.g. to
+    /// open the next run with it. Only the in-memory deposit cache can be captured.
+    pub async fn snapshot(&mut self) -> Result<Snapshot, Error> {
+        self.restore();
+        self.ensure_in_memory()?;
+        self.capture().await.map(Snapshot::new)
+    }
+
+    // Snapshots and checkpoints only capture the in-memory deposit cache.
+    fn ensure_in_memory(&self) -> Result<(), Error> {
+        match self.backend.is_in_memory() {
+            true => Ok(()),
+            false => Err(Error::SnapshotOnDisk),
+        }
+    }
+
+    // Spawns the shards of the clients from the checkpoint or snapshot, if there is one to
+    // restore.
+    fn restore(&mut self) {
+        let Some(snapshots) = self.restored.take() else {
+            return;
+        };
+        let mut shards: HashMap<usize, Vec<ClientProcessor<Cache>>> = HashMap::new();
+        for snapshot in snapshots {
+            let client =
+                ClientProcessor::restore(&snapshot, &self.backend, self.withdrawal_dispute_policy);
+            // Only if the backend was changed to the disk after the clients were given.
+            let Some(client) = client else {
+                tracing::error!("clients can not be restored with the deposits kept on disk");
+                return;
+            };
+            let shard = usize::from(client.client()) % self.shard_count;
+            shards.entry(shard).or_default().push(client);
+        }
+        for (shard, clients) in shards {
+            self.spawn_shard(shard, clients);
+        }
+    }
+
+    // Captures all clients once every shard has processed the records fed so far, so that
+    // the checkpoint matches the position. Failures are logged, the processing goes on.
+    async fn checkpoint(&mut self) {
+        self.since_checkpoint = 0;
+        let Some(recovery) = &self.recovery else {
+            return;
+        };
+        if !self.backend.is_in_memory() {
+            tracing::error!("checkpoints need the deposits to be kept in memory");
+            return;
+        }
+        let clients = match self.capture().await {
+            Ok(clients) => clients,
+            Err(err) => {
+                tracing::error!(%err, "could not checkpoint");
+                return;
+            }
+        };
+        let reports = match self.report_lengths().await {
+            Ok(reports) => reports,
+            Err(err) => {
+                tracing::error!(%err, "could not checkpoint");
+                return;
+            }
+        };
+        if let Err(err) = recovery
+            .checkpoint(self.inputs_fed, self.line, reports, clients)
+            .await
+        {
+            tracing::error!(%err, "could not write checkpoint");
+        }
+    }
+
+    // Collects the clients of all shards, ordered by the client ID. The requests queue up
+    // behind the records sent before, so the clients reflect all of them.
+    async fn capture(&self) -> Result<Vec<ClientSnapshot>, Error> {
+        let mut clients = Vec::new();
+        for (shard, sender) in &self.shards {
+            let (reply, snapshots) = oneshot::channel();
+            let unavailable = |reason: String| Error::CouldNotReceiveResults {
+                shard: *shard,
+                reason,
+            };
+            sender
+                .send(Request::Snapshot { reply })
+                .await
+                .map_err(|err| unavailable(err.to_string()))?;
+            clients.extend(
+                snapshots
+                    .await
+                    .map_err(|err| unavailable(err.to_string()))?,
+            );
+        }
+        clients.sort_by_key(|client| client.client);
+        Ok(clients)
+    }
+
+    // Lengths of the reports once the rows sent so far are written. After `capture`, these
+    // are all rows of the records fed so far.
+    async fn report_lengths(&self) -> std::io::Result<ReportLengths> {
+        let rejections = match &self.rejections {
+            Some(rejections) => Some(rejections.mark().await?),
+            None => None,
+        };
+        let outcomes = match &self.outcomes {
+            Some(outcomes) => Some(outcomes.mark().await?),
+            None => None,
+        };
+        Ok(ReportLengths::new(rejections, outcomes))
+    }
+
+    // Returns the channel of the shard responsible for `client`, spawning the shard if needed.
+    fn shard_sender(&mut self, client: u16) -> mpsc::Sender<Request> {
+        let shard = usize::from(client) % self.shard_count;
+        if let Some(tx_sender) = self.shards.get(&shard) {
+            return tx_sender.clone();
+        }
+        self.spawn_shard(shard, Vec::new())
+    }
+
+    fn spawn_shard(
+        &mut self,
+        shard: usize,
+        clients: Vec<ClientProcessor<Cache>>,
+    ) -> mpsc::Sender<Request> {
+        let (tx_sender, tx_receiver) = mpsc::channel(TX_CHANNEL_SIZE);
+        let (result_sender, result_receiver) = oneshot::channel();
+        let mut shard_worker = Shard::new(
+            self.backend.clone(),
+            self.withdrawal_dispute_policy,
+            tx_receiver,
+            result_sender,
+            self.rejections.clone(),
+            self.outcomes.clone(),
+            self.recovery.clone(),
+        )
+        .with_clients(clients);
+        self.shards.insert(shard, tx_sender.clone());
+        self.result_receivers.insert(shard, result_receiver);
+        tokio::spawn(async move { shard_worker.crank().await });
+        tx_sender
+    }
+
+    // Sends the transaction to its shard and waits until it is processed.
+    async fn apply(&mut self, line: u64, tx: Transaction) -> Result<(), error::Error> {
+        let client = tx.client();
+        let (job, reply) = Job::with_reply(self.source.clone(), line, tx);
+        if self
+            .shard_sender(client)
+            .send(Request::Apply(job))
+            .await
+            .is_err()
+        {
+            return Err(error::Error::ShardUnavailable { client });
+        }
+        reply
+            .await
+            .map_err(|_| error::Error::ShardUnavailable { client })?
+    }
+
+    // Moves the funds between two clients, all or nothing. The source is debited first
+    // and only then the destination is credited. Both steps are awaited before the next
+    // record is read, so no other transaction of either client can get in between and the
+    // debit can always be rolled back.
+    //
+    // The price is that the whole input stalls for two round trips to the shards, while the
+    // other shards sit idle. Inputs which are mostly transfers are processed several times
+    // slower than deposits, see the README.
+    async fn transfer(
+        &mut self,
+        line: u64,
+        transfer: TransactionPayload<Transfer>,
+    ) -> Result<(), error::Error> {
+        let (source, destination, id, amount, currency) = (
+            transfer.client(),
+            transfer.destination(),
+            transfer.tx(),
+            *transfer.amount(),
+            transfer.currency(),
+        );
+        self.apply(line, Transaction::Transfer(transfer)).await?;
+        let credit = TransactionPayload::<Credit>::new(destination, id, amount, currency);
+        if let Err(err) = self.apply(line, Transaction::Credit(credit)).await {
+            let refund = TransactionPayload::<Credit>::refund(source, id, amount, currency);
+            if let Err(err) = self.apply(line, Transaction::Credit(refund)).await {
+                tracing::error!(%err, line, tx = id, "failed to roll back transfer");
+            }
+            return Err(err);
+        }
+        Ok(())
+    }
+
+    async fn reject(&self, rejection: Rejection) {
+        if let Some(rejections) = &self.rejections {
+            if let Err(err) = rejections.send(rejection).await {
+                tracing::error!(%err, "failed to report rejection");
+            }
+        }
     }
 }
 
-async fn send_and_register(
-    tx: Transaction,
-    active_tx_clone: Arc<AtomicUsize>,
-    sender: &mpsc::Sender<Transaction>,
-) {
-    active_tx_clone.fetch_add(1, Ordering::SeqCst);
-    if let Err(_err) = sender.send(tx).await {
-        //tracing::error!(%_err);
+async fn send(job: Job, sender: &mpsc::Sender<Request>) {
+    if let Err(err) = sender.send(Request::Apply(job)).await {
+        tracing::error!(%err, "failed to send transaction to the shard");
     };
 }
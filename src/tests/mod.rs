@@ -1,15 +1,25 @@
 use csv_async::{AsyncDeserializer, AsyncReaderBuilder, AsyncSerializer};
-use csv_diff::{csv::Csv, csv_diff::CsvByteDiff};
+use csv_diff::{csv::Csv, csv_diff::CsvByteDiffBuilder};
 use futures_util::{Stream, StreamExt};
-use rust_decimal::Decimal;
 use std::{
     io::{BufReader, Cursor, Read},
+    num::NonZeroUsize,
     path::{Path, PathBuf},
+    sync::Arc,
 };
+use test_case::test_case;
 use tokio_util::compat::{Compat, TokioAsyncReadCompatExt};
 use walkdir::WalkDir;
 
-use crate::{StreamProcessor, client_processor::ClientState, csv, stream_processor::Error};
+use crate::{
+    StreamProcessor,
+    amount::{Amount, ExcessPrecision, InputAmount, Precision},
+    client_processor::{ClientState, WithdrawalDisputePolicy},
+    csv,
+    db::{Backend, in_mem, on_disk},
+    json, jsonl, outcome, recovery, rejection, snapshot,
+    stream_processor::Error,
+};
 
 fn files_matching_pattern_from_dir<P: AsRef<Path>>(dir: P, pattern: &str) -> Vec<PathBuf> {
     WalkDir::new(dir.as_ref())
@@ -27,7 +37,17 @@ fn files_matching_pattern_from_dir<P: AsRef<Path>>(dir: P, pattern: &str) -> Vec
 }
 
 const SCENARIOS_PATH: &str = "./src/tests/scenarios";
-const EXPECTED_SCENARIO_COUNT: usize = 35;
+const EXPECTED_SCENARIO_COUNT: usize = 56;
+const WITHDRAWAL_DISPUTES_PATH: &str = "./src/tests/withdrawal_disputes";
+const EXPECTED_WITHDRAWAL_DISPUTE_SCENARIO_COUNT: usize = 4;
+const PRECISION_PATH: &str = "./src/tests/precision";
+const EXPECTED_PRECISION_SCENARIO_COUNT: usize = 1;
+const REJECTIONS_PATH: &str = "./src/tests/rejections/all_reasons.in";
+const OUTCOMES_PATH: &str = "./src/tests/outcomes/transactions.in";
+const JSON_OUTPUT_PATH: &str = "./src/tests/json_output/open_disputes.in";
+const MULTIPLE_INPUTS_PATH: &str = "./src/tests/multiple_inputs";
+const EXPECTED_MULTIPLE_INPUTS_COUNT: usize = 3;
+const SNAPSHOT_CURRENCIES_PATH: &str = "./src/tests/snapshot_currencies";
 
 async fn csv_deserializer_from_file<P: AsRef<Path>>(
     path: P,
@@ -44,17 +64,26 @@ async fn csv_deserializer_from_file<P: AsRef<Path>>(
 
 async fn result_stream_to_csv(
     mut results: impl Stream<Item = Result<ClientState, Error>> + Unpin,
+    with_currency: bool,
+    decimal_places: usize,
 ) -> Csv<Box<dyn std::io::Read + std::marker::Send>> {
     let mut buffer = Vec::new();
     {
         let mut writer = AsyncSerializer::from_writer(&mut buffer);
 
         while let Some(client_state) = results.next().await {
-            let record: csv::OutputRecord = client_state.unwrap().try_into().unwrap();
-            writer
-                .serialize(&record)
-                .await
-                .expect("should serialize output record");
+            let records = csv::OutputRecord::from_client_state(
+                &client_state.unwrap(),
+                with_currency,
+                decimal_places,
+            )
+            .unwrap();
+            for record in records {
+                writer
+                    .serialize(&record)
+                    .await
+                    .expect("should serialize output record");
+            }
         }
         writer.flush().await.expect("should flush writer");
     }
@@ -72,40 +101,610 @@ fn expected_csv_from_input_file(path: &Path) -> Csv<Box<dyn Read + Send>> {
     Csv::with_reader(expected_file_reader)
 }
 
+// Columns identifying a row of the client states. A client has a row per currency, so the
+// currency is needed to identify it, if there is one.
+fn client_key(with_currency: bool) -> &'static [usize] {
+    match with_currency {
+        true => &[0, 1],
+        false => &[0],
+    }
+}
+
+// The input and the line identify a rejected record.
+const REJECTION_KEY: &[usize] = &[0, 1];
+
 fn assert_csv(
     actual: Csv<Box<dyn Read + Send>>,
     expected: Csv<Box<dyn Read + Send>>,
     path: &PathBuf,
+    key_columns: &[usize],
 ) {
-    let csv_diff = CsvByteDiff::new().expect("should create csv diff");
+    let csv_diff = CsvByteDiffBuilder::new()
+        .primary_key_columns(key_columns.iter().copied())
+        .build()
+        .expect("should create csv diff");
     let diff_iterator = csv_diff.diff(expected, actual);
 
     let diffs = diff_iterator.collect::<Vec<_>>();
     assert!(diffs.is_empty(), "mismatch in scenario: {:?}", path);
 }
 
-#[tokio::test]
-async fn scenarios() {
+fn in_memory_processor() -> StreamProcessor<InputAmount> {
+    StreamProcessor::new()
+}
+
+fn on_disk_processor() -> StreamProcessor<InputAmount> {
+    // The file is removed when `file` is dropped, but the store keeps it open.
+    let file = tempfile::NamedTempFile::new().expect("should create temporary file");
+    let store = on_disk::Store::create(file.path()).expect("should create deposit store");
+    StreamProcessor::new().with_backend(Backend::OnDisk {
+        store: Arc::new(store),
+    })
+}
+
+fn sharded_processor() -> StreamProcessor<InputAmount> {
+    StreamProcessor::new().with_shard_count(NonZeroUsize::new(2).expect("non-zero"))
+}
+
+// Runs every scenario found in `dir` and returns how many there were.
+async fn run_scenarios<P, F>(dir: P, stream_processor: F) -> usize
+where
+    P: AsRef<Path>,
+    F: Fn() -> StreamProcessor<InputAmount>,
+{
+    run_scenarios_with_decimal_places(dir, stream_processor, Amount::DECIMAL_PLACES).await
+}
+
+// Same as `run_scenarios`, but the balances are output with `decimal_places`.
+async fn run_scenarios_with_decimal_places<P, F>(
+    dir: P,
+    stream_processor: F,
+    decimal_places: usize,
+) -> usize
+where
+    P: AsRef<Path>,
+    F: Fn() -> StreamProcessor<InputAmount>,
+{
     // TODO: Scenarios could be run in parallel if implemented as separate tests.
     let mut count = 0;
-    for path in files_matching_pattern_from_dir(SCENARIOS_PATH, "in") {
+    for path in files_matching_pattern_from_dir(dir, "in") {
         // Read input
         let mut input = csv_deserializer_from_file(&path).await;
-        let mut input_stream = input.deserialize::<csv::InputRecord<Decimal>>();
+        let with_currency = csv::has_currency_column(&mut input)
+            .await
+            .expect("should read headers");
+        let mut input_stream = csv::records::<_, InputAmount>(&mut input);
 
         // Do the actual processing
-        let mut stream_processor = StreamProcessor::new();
+        let mut stream_processor = stream_processor();
         let results_stream = stream_processor.process(&mut input_stream).await;
 
         // Compare results
-        let actual_csv = result_stream_to_csv(results_stream).await;
+        let actual_csv = result_stream_to_csv(results_stream, with_currency, decimal_places).await;
         let expected_csv = expected_csv_from_input_file(&path);
-        assert_csv(actual_csv, expected_csv, &path);
+        assert_csv(actual_csv, expected_csv, &path, client_key(with_currency));
 
         count += 1;
     }
+    count
+}
+
+#[test_case(in_memory_processor ; "in memory")]
+#[test_case(on_disk_processor ; "on disk")]
+#[test_case(sharded_processor ; "two shards")]
+#[tokio::test]
+async fn scenarios(stream_processor: fn() -> StreamProcessor<InputAmount>) {
+    let count = run_scenarios(SCENARIOS_PATH, stream_processor).await;
     assert_eq!(
         count, EXPECTED_SCENARIO_COUNT,
         "incorrect number of scenarios tested"
     );
 }
+
+#[test_case(WithdrawalDisputePolicy::Hold, "hold" ; "hold")]
+#[test_case(WithdrawalDisputePolicy::NoHold, "no_hold" ; "no hold")]
+#[tokio::test]
+async fn withdrawal_dispute_scenarios(policy: WithdrawalDisputePolicy, dir: &str) {
+    let dir = Path::new(WITHDRAWAL_DISPUTES_PATH).join(dir);
+    let count = run_scenarios(dir, || {
+        StreamProcessor::new().with_withdrawal_dispute_policy(policy)
+    })
+    .await;
+    assert_eq!(
+        count, EXPECTED_WITHDRAWAL_DISPUTE_SCENARIO_COUNT,
+        "incorrect number of scenarios tested"
+    );
+}
+
+#[test_case(ExcessPrecision::Reject, "reject" ; "reject")]
+#[test_case(ExcessPrecision::RoundHalfEven, "round_half_even" ; "round half even")]
+#[test_case(ExcessPrecision::RoundHalfUp, "round_half_up" ; "round half up")]
+#[test_case(ExcessPrecision::Truncate, "truncate" ; "truncate")]
+#[tokio::test]
+async fn precision_scenarios(excess: ExcessPrecision, dir: &str) {
+    let dir = Path::new(PRECISION_PATH).join(dir);
+    let precision = Precision::new(2, excess).expect("valid precision");
+    let count = run_scenarios_with_decimal_places(
+        dir,
+        || StreamProcessor::new().with_precision(precision),
+        precision.decimal_places(),
+    )
+    .await;
+    assert_eq!(
+        count, EXPECTED_PRECISION_SCENARIO_COUNT,
+        "incorrect number of scenarios tested"
+    );
+}
+
+// Feeds the inputs to a single stream processor, one after another, each of them named by
+// its file name. Returns the final client states and the rejections written as CSV.
+async fn process_inputs(paths: &[PathBuf]) -> (Vec<Result<ClientState, Error>>, Vec<u8>) {
+    let (sender, receiver) = rejection::channel();
+    let writer = tokio::spawn(async move {
+        let mut buffer = Vec::new();
+        rejection::write_csv(receiver, &mut buffer, 0)
+            .await
+            .expect("should write rejections");
+        buffer
+    });
+
+    let mut stream_processor = StreamProcessor::new().with_rejections(sender);
+    for path in paths {
+        let mut input = csv_deserializer_from_file(path).await;
+        let mut input_stream = csv::records::<_, InputAmount>(&mut input);
+        let source = path
+            .file_name()
+            .expect("should be a file")
+            .to_string_lossy();
+        stream_processor
+            .feed(Some(source.into()), &mut input_stream)
+            .await;
+    }
+    let results = stream_processor.finish().await.collect().await;
+    drop(stream_processor);
+
+    let buffer = writer.await.expect("should collect rejections");
+    (results, buffer)
+}
+
+fn csv_from_buffer(buffer: Vec<u8>) -> Csv<Box<dyn Read + Send>> {
+    let reader: Box<dyn Read + Send> = Box::new(Cursor::new(buffer));
+    Csv::with_reader(reader)
+}
+
+#[tokio::test]
+async fn rejections() {
+    let path = PathBuf::from(REJECTIONS_PATH);
+    let (_, rejections) = process_inputs(std::slice::from_ref(&path)).await;
+    assert_csv(
+        csv_from_buffer(rejections),
+        expected_csv_from_input_file(&path),
+        &path,
+        REJECTION_KEY,
+    );
+}
+
+#[tokio::test]
+async fn multiple_inputs() {
+    let mut paths = files_matching_pattern_from_dir(MULTIPLE_INPUTS_PATH, "in");
+    paths.sort();
+    assert_eq!(
+        paths.len(),
+        EXPECTED_MULTIPLE_INPUTS_COUNT,
+        "incorrect number of inputs"
+    );
+    let (results, rejections) = process_inputs(&paths).await;
+
+    let dir = Path::new(MULTIPLE_INPUTS_PATH);
+    let balances_path = dir.join("balances.out");
+    let actual_csv = result_stream_to_csv(
+        futures_util::stream::iter(results),
+        false,
+        Amount::DECIMAL_PLACES,
+    )
+    .await;
+    assert_csv(
+        actual_csv,
+        expected_csv_from_input_file(&balances_path),
+        &balances_path,
+        client_key(false),
+    );
+    let rejections_path = dir.join("rejections.out");
+    assert_csv(
+        csv_from_buffer(rejections),
+        expected_csv_from_input_file(&rejections_path),
+        &rejections_path,
+        REJECTION_KEY,
+    );
+}
+
+#[tokio::test]
+async fn json_output() {
+    let path = PathBuf::from(JSON_OUTPUT_PATH);
+    let mut input = csv_deserializer_from_file(&path).await;
+    let with_currency = csv::has_currency_column(&mut input)
+        .await
+        .expect("should read headers");
+    let mut input_stream = csv::records::<_, InputAmount>(&mut input);
+    let mut stream_processor = in_memory_processor();
+    let states: Vec<_> = stream_processor
+        .process(&mut input_stream)
+        .await
+        .map(|state| state.expect("should receive client state"))
+        .collect()
+        .await;
+
+    let mut actual = String::new();
+    for state in &states {
+        let records =
+            json::OutputRecord::from_client_state(state, with_currency, Amount::DECIMAL_PLACES)
+                .expect("should create output records");
+        for record in records {
+            actual += &serde_json::to_string(&record).expect("should serialize output record");
+            actual.push('\n');
+        }
+    }
+    let expected =
+        std::fs::read_to_string(path.with_extension("jsonl")).expect("should read expected file");
+    assert_eq!(actual, expected, "mismatch in scenario: {:?}", path);
+}
+
+#[test_case(in_memory_processor ; "in memory")]
+#[test_case(on_disk_processor ; "on disk")]
+#[test_case(sharded_processor ; "sharded")]
+#[tokio::test]
+async fn results_are_sorted_by_client(stream_processor: fn() -> StreamProcessor<InputAmount>) {
+    let mut input = String::from("type,client,tx,amount\n");
+    for (tx, client) in (1..=100).rev().enumerate() {
+        input += &format!("deposit,{},{},1.0\n", client, tx + 1);
+    }
+    let mut reader = AsyncReaderBuilder::new()
+        .trim(csv_async::Trim::All)
+        .create_deserializer(input.as_bytes());
+    let mut input_stream = csv::records::<_, InputAmount>(&mut reader);
+    let clients: Vec<_> = stream_processor()
+        .process(&mut input_stream)
+        .await
+        .map(|state| state.expect("should receive client state").client())
+        .collect()
+        .await;
+    assert_eq!(clients, (1..=100).collect::<Vec<u16>>());
+}
+
+#[tokio::test]
+async fn client_state_while_feeding() {
+    let mut reader = AsyncReaderBuilder::new()
+        .trim(csv_async::Trim::All)
+        .create_deserializer("type,client,tx,amount\ndeposit,1,1,5.0\ndispute,1,1,\n".as_bytes());
+    let mut input_stream = csv::records::<_, InputAmount>(&mut reader);
+    let mut stream_processor = sharded_processor();
+    stream_processor.feed(None, &mut input_stream).await;
+
+    let state = stream_processor
+        .client_state(1)
+        .await
+        .expect("should query client state")
+        .expect("client should exist");
+    let (_, balances) = state.balances().next().expect("should have balances");
+    assert_eq!(balances.held().fixed(1).to_string(), "5.0");
+    assert_eq!(state.open_disputes(None), 1);
+    assert!(
+        stream_processor
+            .client_state(2)
+            .await
+            .expect("should query client state")
+            .is_none()
+    );
+
+    // The processor keeps going after the query.
+    let mut reader = AsyncReaderBuilder::new()
+        .trim(csv_async::Trim::All)
+        .create_deserializer("type,client,tx,amount\nresolve,1,1,\n".as_bytes());
+    let mut input_stream = csv::records::<_, InputAmount>(&mut reader);
+    stream_processor.feed(None, &mut input_stream).await;
+    let states: Vec<_> = stream_processor.finish().await.collect().await;
+    let state = states[0].as_ref().expect("should receive client state");
+    assert_eq!(state.open_disputes(None), 0);
+}
+
+#[tokio::test]
+async fn pruned_id_can_not_be_reused() {
+    let input =
+        "type,client,tx,amount\ndeposit,1,1,10\ndeposit,1,2,5\ndeposit,1,1,7\ndispute,1,1,\n";
+    let mut reader = AsyncReaderBuilder::new()
+        .trim(csv_async::Trim::All)
+        .create_deserializer(input.as_bytes());
+    let mut input_stream = csv::records::<_, InputAmount>(&mut reader);
+    let max_size = NonZeroUsize::new(1).expect("non-zero");
+    let mut stream_processor = StreamProcessor::new().with_backend(Backend::InMemory {
+        pruning_strategy: Some(in_mem::PruningStrategy::Size { max_size }),
+    });
+    let states: Vec<_> = stream_processor
+        .process(&mut input_stream)
+        .await
+        .collect()
+        .await;
+    let state = states[0].as_ref().expect("should receive client state");
+    let (_, balances) = state.balances().next().expect("should have balances");
+    assert_eq!(balances.available().fixed(0).to_string(), "15");
+    assert_eq!(balances.held().fixed(0).to_string(), "0");
+}
+
+#[tokio::test]
+async fn outcomes() {
+    let path = PathBuf::from(OUTCOMES_PATH);
+    let (sender, receiver) = outcome::channel();
+    let writer = tokio::spawn(async move {
+        let mut buffer = Vec::new();
+        outcome::write_csv(receiver, &mut buffer, 0, Amount::DECIMAL_PLACES)
+            .await
+            .expect("should write outcomes");
+        buffer
+    });
+
+    // A single shard keeps the outcomes in the input order.
+    let mut stream_processor = StreamProcessor::new()
+        .with_shard_count(NonZeroUsize::new(1).expect("non-zero"))
+        .with_outcomes(sender);
+    let mut input = csv_deserializer_from_file(&path).await;
+    let mut input_stream = csv::records::<_, InputAmount>(&mut input);
+    stream_processor
+        .feed(Some("transactions.in".into()), &mut input_stream)
+        .await;
+    let _: Vec<_> = stream_processor.finish().await.collect().await;
+    drop(stream_processor);
+
+    let actual = String::from_utf8(writer.await.expect("should collect outcomes"))
+        .expect("valid utf8 string");
+    let expected =
+        std::fs::read_to_string(path.with_extension("out")).expect("should read expected file");
+    assert_eq!(actual, expected, "mismatch in scenario: {:?}", path);
+}
+
+// Writes the outcomes of the stream processor after the `kept` ones, until it is dropped.
+fn write_outcomes(
+    stream_processor: StreamProcessor<InputAmount>,
+    kept: Vec<u8>,
+) -> (
+    StreamProcessor<InputAmount>,
+    tokio::task::JoinHandle<Vec<u8>>,
+) {
+    let (sender, receiver) = outcome::channel();
+    let writer = tokio::spawn(async move {
+        let mut buffer = kept;
+        let len = buffer.len() as u64;
+        outcome::write_csv(receiver, &mut buffer, len, Amount::DECIMAL_PLACES)
+            .await
+            .expect("should write outcomes");
+        buffer
+    });
+    (stream_processor.with_outcomes(sender), writer)
+}
+
+// Feeds the inputs to a single shard, so that the audit log is in the input order.
+async fn process_with_recovery(
+    mut stream_processor: StreamProcessor<InputAmount>,
+    paths: &[PathBuf],
+) -> Vec<Result<ClientState, Error>> {
+    for path in paths {
+        let mut input = csv_deserializer_from_file(path).await;
+        let mut input_stream = csv::records::<_, InputAmount>(&mut input);
+        let source = path
+            .file_name()
+            .expect("should be a file")
+            .to_string_lossy();
+        stream_processor
+            .feed(Some(source.into()), &mut input_stream)
+            .await;
+    }
+    stream_processor.finish().await.collect().await
+}
+
+#[tokio::test]
+async fn resume_from_checkpoint() {
+    let mut paths = files_matching_pattern_from_dir(MULTIPLE_INPUTS_PATH, "in");
+    paths.sort();
+    let single_shard = || {
+        StreamProcessor::<InputAmount>::new()
+            .with_shard_count(NonZeroUsize::new(1).expect("non-zero"))
+    };
+    let interval = NonZeroUsize::new(3).expect("non-zero");
+
+    // An uninterrupted run, for reference.
+    let uninterrupted = tempfile::tempdir().expect("should create temporary directory");
+    let recovery =
+        recovery::RecoveryDir::create(uninterrupted.path()).expect("should create recovery");
+    let (stream_processor, writer) =
+        write_outcomes(single_shard().with_recovery(recovery, interval), Vec::new());
+    process_with_recovery(stream_processor, &paths).await;
+    let uninterrupted_outcomes = writer.await.expect("should collect outcomes");
+
+    // A run which stops in the middle of the second input, after the checkpoint at the
+    // third record. The audit log entries and outcomes written after the checkpoint must be
+    // dropped.
+    let crashed = tempfile::tempdir().expect("should create temporary directory");
+    let recovery = recovery::RecoveryDir::create(crashed.path()).expect("should create recovery");
+    let (mut stream_processor, writer) =
+        write_outcomes(single_shard().with_recovery(recovery, interval), Vec::new());
+    for (path, records) in paths.iter().zip([usize::MAX, 2]) {
+        let mut input = csv_deserializer_from_file(path).await;
+        let input_stream = csv::records::<_, InputAmount>(&mut input).take(records);
+        let source = path
+            .file_name()
+            .expect("should be a file")
+            .to_string_lossy();
+        stream_processor
+            .feed(Some(source.into()), input_stream)
+            .await;
+    }
+    let resumed = tempfile::tempdir().expect("should create temporary directory");
+    for file in ["checkpoint.json", "audit_log.jsonl"] {
+        std::fs::copy(crashed.path().join(file), resumed.path().join(file))
+            .expect("should copy the state of the crashed run");
+    }
+    drop(stream_processor);
+    let mut outcomes = writer.await.expect("should collect outcomes");
+    outcomes.extend_from_slice(b"written,after,the,checkpoint\n");
+    let mut audit_log = std::fs::OpenOptions::new()
+        .append(true)
+        .open(resumed.path().join("audit_log.jsonl"))
+        .expect("should open audit log");
+    std::io::Write::write_all(&mut audit_log, b"{\"written\":\"after the checkpoint\"}\n")
+        .expect("should write audit log");
+
+    let (recovery, checkpoint) =
+        recovery::RecoveryDir::resume(resumed.path()).expect("should resume recovery");
+    let checkpoint = checkpoint.expect("should have a checkpoint");
+    assert_eq!((checkpoint.inputs(), checkpoint.line()), (1, 2));
+    let outcomes_len = checkpoint
+        .reports()
+        .outcomes()
+        .expect("should have outcomes");
+    outcomes.truncate(outcomes_len.try_into().expect("should fit"));
+    let stream_processor = single_shard()
+        .with_recovery(recovery, interval)
+        .with_checkpoint(checkpoint)
+        .expect("should take checkpoint");
+    let (stream_processor, writer) = write_outcomes(stream_processor, outcomes);
+    let results = process_with_recovery(stream_processor, &paths).await;
+    let resumed_outcomes = writer.await.expect("should collect outcomes");
+
+    let balances_path = Path::new(MULTIPLE_INPUTS_PATH).join("balances.out");
+    let actual_csv = result_stream_to_csv(
+        futures_util::stream::iter(results),
+        false,
+        Amount::DECIMAL_PLACES,
+    )
+    .await;
+    assert_csv(
+        actual_csv,
+        expected_csv_from_input_file(&balances_path),
+        &balances_path,
+        client_key(false),
+    );
+    let audit_log = |dir: &Path| {
+        std::fs::read_to_string(dir.join("audit_log.jsonl")).expect("should read audit log")
+    };
+    assert_eq!(audit_log(resumed.path()), audit_log(uninterrupted.path()));
+    assert_eq!(
+        String::from_utf8(resumed_outcomes).expect("valid utf8 string"),
+        String::from_utf8(uninterrupted_outcomes).expect("valid utf8 string")
+    );
+}
+
+// Feeds the inputs to the stream processor, one after another.
+async fn feed_inputs(stream_processor: &mut StreamProcessor<InputAmount>, paths: &[PathBuf]) {
+    for path in paths {
+        let mut input = csv_deserializer_from_file(path).await;
+        let mut input_stream = csv::records::<_, InputAmount>(&mut input);
+        stream_processor.feed(None, &mut input_stream).await;
+    }
+}
+
+#[tokio::test]
+async fn snapshot_as_opening_state() {
+    let mut paths = files_matching_pattern_from_dir(MULTIPLE_INPUTS_PATH, "in");
+    paths.sort();
+    let (yesterday, today) = paths.split_at(2);
+
+    // The last input resolves a dispute and charges back a deposit of the earlier ones,
+    // so both the disputes and the deposit cache must be carried over.
+    let dir = tempfile::tempdir().expect("should create temporary directory");
+    let snapshot_path = dir.path().join("snapshot.json");
+    let mut stream_processor = StreamProcessor::new();
+    feed_inputs(&mut stream_processor, yesterday).await;
+    stream_processor
+        .snapshot()
+        .await
+        .expect("should capture snapshot")
+        .write(&snapshot_path)
+        .expect("should write snapshot");
+
+    let snapshot = snapshot::Snapshot::read(&snapshot_path).expect("should read snapshot");
+    assert_eq!(snapshot.client_count(), 2);
+    let mut stream_processor = StreamProcessor::new()
+        .with_snapshot(snapshot)
+        .expect("should take snapshot");
+    // The restored clients can be queried before any record is fed, e.g. while serving.
+    let state = stream_processor
+        .client_state(2)
+        .await
+        .expect("should query client state")
+        .expect("client should be restored");
+    let (_, balances) = state.balances().next().expect("should have balances");
+    assert_eq!(balances.available().fixed(0).to_string(), "60");
+    feed_inputs(&mut stream_processor, today).await;
+    let results: Vec<_> = stream_processor.finish().await.collect().await;
+
+    let balances_path = Path::new(MULTIPLE_INPUTS_PATH).join("balances.out");
+    let actual_csv = result_stream_to_csv(
+        futures_util::stream::iter(results),
+        false,
+        Amount::DECIMAL_PLACES,
+    )
+    .await;
+    assert_csv(
+        actual_csv,
+        expected_csv_from_input_file(&balances_path),
+        &balances_path,
+        client_key(false),
+    );
+}
+
+// An input which does not match the currency column of the snapshot is refused from its
+// start: a CSV input by its header, the records of a JSON Lines input with an amount one by
+// one. Records without an amount still apply.
+#[test_case("eur_snapshot_plain_input", true ; "snapshot with currencies")]
+#[test_case("plain_snapshot_eur_input", false ; "snapshot without currencies")]
+#[tokio::test]
+async fn snapshot_decides_currency_column(scenario: &str, with_currency: bool) {
+    let path = Path::new(SNAPSHOT_CURRENCIES_PATH).join(scenario);
+    let snapshot =
+        snapshot::Snapshot::read(path.with_extension("json")).expect("should read snapshot");
+    let currency_column = snapshot.currency_column();
+    assert_eq!(currency_column.present(), Some(with_currency));
+
+    let mut input = csv_deserializer_from_file(path.with_extension("in")).await;
+    let has_currency = csv::has_currency_column(&mut input)
+        .await
+        .expect("should read headers");
+    assert!(!currency_column.agrees(has_currency));
+
+    let file = tokio::fs::File::open(path.with_extension("jsonl"))
+        .await
+        .expect("should read scenario file")
+        .compat();
+    let mut records = jsonl::records_with(futures_util::io::BufReader::new(file), currency_column);
+    let mut stream_processor = in_memory_processor()
+        .with_snapshot(snapshot)
+        .expect("should take snapshot");
+    stream_processor.feed(None, &mut records).await;
+    let actual_csv = result_stream_to_csv(
+        stream_processor.finish().await,
+        with_currency,
+        Amount::DECIMAL_PLACES,
+    )
+    .await;
+    let expected_path = path.with_extension("out");
+    assert_csv(
+        actual_csv,
+        expected_csv_from_input_file(&expected_path),
+        &expected_path,
+        client_key(with_currency),
+    );
+}
+
+#[tokio::test]
+async fn snapshot_needs_in_memory_deposits() {
+    let mut stream_processor = on_disk_processor();
+    let result = stream_processor.snapshot().await;
+    assert!(matches!(result, Err(Error::SnapshotOnDisk)));
+
+    // Nor can the clients of a snapshot or checkpoint be restored.
+    let result = on_disk_processor().with_snapshot(snapshot::Snapshot::new(Vec::new()));
+    assert!(matches!(result, Err(Error::SnapshotOnDisk)));
+    let checkpoint =
+        recovery::Checkpoint::new(0, 0, 0, recovery::ReportLengths::default(), Vec::new());
+    let result = on_disk_processor().with_checkpoint(checkpoint);
+    assert!(matches!(result, Err(Error::SnapshotOnDisk)));
+}
@@ -1,65 +1,106 @@
 //! A module consisting of types and functions to handle transactions.
 
-use crate::NonZero;
+use crate::{NonZero, currency::Currency};
 
 pub struct Deposit;
 pub struct Withdrawal;
 pub struct Dispute;
 pub struct Resolve;
 pub struct Chargeback;
+pub struct Transfer {
+    // The client receiving the funds.
+    destination: u16,
+}
+// The receiving side of a transfer. Never read from the input, it is created by the stream
+// processor, which also uses it to return the funds when the transfer can not be completed.
+pub struct Credit {
+    // Whether the funds go back to the source of a transfer that could not be completed.
+    refund: bool,
+}
+pub struct Unlock {
+    // Who reviewed the account and allowed it to be used again.
+    authorised_by: String,
+}
 
-// The main transaction type.
-pub(super) enum Transaction {
+/// A transaction validated and ready to be applied to the client state.
+pub enum Transaction {
     Deposit(TransactionPayload<Deposit>),
     Withdrawal(TransactionPayload<Withdrawal>),
     Dispute(TransactionPayload<Dispute>),
     Resolve(TransactionPayload<Resolve>),
     Chargeback(TransactionPayload<Chargeback>),
+    Transfer(TransactionPayload<Transfer>),
+    Credit(TransactionPayload<Credit>),
+    // Boxed, since unlocks are rare and should not grow the size of every other transaction.
+    Unlock(Box<TransactionPayload<Unlock>>),
 }
 
 impl Transaction {
-    pub(super) fn client(&self) -> u16 {
+    pub fn client(&self) -> u16 {
         match self {
             Self::Deposit(tx) => tx.client(),
             Self::Withdrawal(tx) => tx.client(),
             Self::Dispute(tx) => tx.client(),
             Self::Resolve(tx) => tx.client(),
             Self::Chargeback(tx) => tx.client(),
+            Self::Transfer(tx) => tx.client(),
+            Self::Credit(tx) => tx.client(),
+            Self::Unlock(tx) => tx.client(),
+        }
+    }
+    pub fn tx(&self) -> u32 {
+        match self {
+            Self::Deposit(tx) => tx.tx(),
+            Self::Withdrawal(tx) => tx.tx(),
+            Self::Dispute(tx) => tx.tx(),
+            Self::Resolve(tx) => tx.tx(),
+            Self::Chargeback(tx) => tx.tx(),
+            Self::Transfer(tx) => tx.tx(),
+            Self::Credit(tx) => tx.tx(),
+            Self::Unlock(tx) => tx.tx(),
         }
     }
 }
 
-// Payload (data) of the transaction.
-pub(super) struct TransactionPayload<Kind> {
+/// Payload (data) of the transaction.
+pub struct TransactionPayload<Kind> {
     client: u16,
     tx: u32,
     // Option, since not all types of transactions have an amount.
     // The `Kind` type parameter ensures that this is correctly handled.
     amount: Option<NonZero>,
-    phantom: std::marker::PhantomData<Kind>,
+    // Currency of the amount. `None` if the input does not specify currencies at all.
+    currency: Option<Currency>,
+    // Data specific to the kind of the transaction. Zero-sized for most of them.
+    details: Kind,
 }
 
 impl<Kind> TransactionPayload<Kind> {
-    pub(super) fn client(&self) -> u16 {
+    pub fn client(&self) -> u16 {
         self.client
     }
 
-    pub(super) fn tx(&self) -> u32 {
+    pub fn tx(&self) -> u32 {
         self.tx
     }
+
+    pub fn currency(&self) -> Option<Currency> {
+        self.currency
+    }
 }
 
 impl TransactionPayload<Deposit> {
-    pub(super) fn new(client: u16, tx: u32, amount: NonZero) -> Self {
+    pub fn new(client: u16, tx: u32, amount: NonZero, currency: Option<Currency>) -> Self {
         Self {
             tx,
             client,
             amount: Some(amount),
-            phantom: std::marker::PhantomData,
+            currency,
+            details: Deposit,
         }
     }
 
-    pub(super) fn amount(&self) -> &NonZero {
+    pub fn amount(&self) -> &NonZero {
         self.amount
             .as_ref()
             .expect("amount guaranteed to be present")
@@ -67,16 +108,17 @@ impl TransactionPayload<Deposit> {
 }
 
 impl TransactionPayload<Withdrawal> {
-    pub(super) fn new(client: u16, tx: u32, amount: NonZero) -> Self {
+    pub fn new(client: u16, tx: u32, amount: NonZero, currency: Option<Currency>) -> Self {
         Self {
             tx,
             client,
             amount: Some(amount),
-            phantom: std::marker::PhantomData,
+            currency,
+            details: Withdrawal,
         }
     }
 
-    pub(super) fn amount(&self) -> &NonZero {
+    pub fn amount(&self) -> &NonZero {
         self.amount
             .as_ref()
             .expect("amount guaranteed to be present")
@@ -84,34 +126,116 @@ impl TransactionPayload<Withdrawal> {
 }
 
 impl TransactionPayload<Dispute> {
-    pub(super) fn new(client: u16, tx: u32) -> Self {
+    pub fn new(client: u16, tx: u32) -> Self {
         Self {
             tx,
             client,
             amount: None,
-            phantom: std::marker::PhantomData,
+            currency: None,
+            details: Dispute,
         }
     }
 }
 
 impl TransactionPayload<Resolve> {
-    pub(super) fn new(client: u16, tx: u32) -> Self {
+    pub fn new(client: u16, tx: u32) -> Self {
         Self {
             tx,
             client,
             amount: None,
-            phantom: std::marker::PhantomData,
+            currency: None,
+            details: Resolve,
         }
     }
 }
 
 impl TransactionPayload<Chargeback> {
-    pub(super) fn new(client: u16, tx: u32) -> Self {
+    pub fn new(client: u16, tx: u32) -> Self {
+        Self {
+            tx,
+            client,
+            amount: None,
+            currency: None,
+            details: Chargeback,
+        }
+    }
+}
+
+impl TransactionPayload<Transfer> {
+    pub fn new(
+        client: u16,
+        tx: u32,
+        amount: NonZero,
+        currency: Option<Currency>,
+        destination: u16,
+    ) -> Self {
+        Self {
+            tx,
+            client,
+            amount: Some(amount),
+            currency,
+            details: Transfer { destination },
+        }
+    }
+
+    pub fn amount(&self) -> &NonZero {
+        self.amount
+            .as_ref()
+            .expect("amount guaranteed to be present")
+    }
+
+    pub fn destination(&self) -> u16 {
+        self.details.destination
+    }
+}
+
+impl TransactionPayload<Credit> {
+    pub(crate) fn new(client: u16, tx: u32, amount: NonZero, currency: Option<Currency>) -> Self {
+        Self {
+            tx,
+            client,
+            amount: Some(amount),
+            currency,
+            details: Credit { refund: false },
+        }
+    }
+
+    /// Returns the funds to the source of a transfer which could not be credited.
+    pub(crate) fn refund(
+        client: u16,
+        tx: u32,
+        amount: NonZero,
+        currency: Option<Currency>,
+    ) -> Self {
+        Self {
+            details: Credit { refund: true },
+            ..Self::new(client, tx, amount, currency)
+        }
+    }
+
+    pub fn is_refund(&self) -> bool {
+        self.details.refund
+    }
+
+    pub fn amount(&self) -> &NonZero {
+        self.amount
+            .as_ref()
+            .expect("amount guaranteed to be present")
+    }
+}
+
+impl TransactionPayload<Unlock> {
+    pub fn new(client: u16, tx: u32, authorised_by: String) -> Self {
         Self {
             tx,
             client,
             amount: None,
-            phantom: std::marker::PhantomData,
+            currency: None,
+            details: Unlock { authorised_by },
         }
     }
+
+    pub fn authorised_by(&self) -> &str {
+        &self.details.authorised_by
+    }
 }